@@ -1,17 +1,38 @@
 use std::collections::HashMap;
 use std::fmt::{Display, Write as _};
+use std::num::NonZeroUsize;
 use std::str::FromStr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 use poise::serenity_prelude::GatewayIntents;
 use poise::{async_trait, CreateReply};
-use protocol::VersionResponse;
+use protocol::syntax::string_literal;
+use protocol::{
+	ConfigResponse, Encoding, LimitsResponse, OutputFormat, PdfInfo, Progress, ReloadedFonts,
+	RenderOptions, Rendered, TiledPage, VersionResponse,
+};
 use rusqlite::{named_params, Connection, OpenFlags};
-use serenity::builder::{CreateAllowedMentions, CreateAttachment};
+use serenity::builder::{
+	CreateActionRow, CreateAllowedMentions, CreateAttachment, CreateButton, CreateEmbed,
+	CreateInteractionResponse, CreateInteractionResponseMessage, CreateThread,
+};
+use serenity::collector::ComponentInteractionCollector;
+use serenity::model::application::ButtonStyle;
+use serenity::model::guild::Role;
+use serenity::model::id::{RoleId, UserId};
+use serenity::model::user::User;
 use tokio::join;
 use tokio::sync::{mpsc, Mutex};
 
-use crate::worker::Worker;
+use crate::history::{self, History};
+use crate::locale::Locale;
+use crate::worker::{
+	RenderInput, Worker, WorkerFailure, BASE_TOTAL_TIMEOUT, MAX_TOTAL_TIMEOUT, PROGRESS_TIMEOUT,
+	TOTAL_TIMEOUT_PER_KIB,
+};
 use crate::SOURCE_URL;
+use crate::{examples, explanations};
 
 /// U+200D is a zero-width joiner.
 /// It prevents the triple backtick from being interpreted as a codeblock but retains ligature support.
@@ -52,20 +73,49 @@ impl FromStr for Theme {
 			"transparent" | "t" => Self::Transparent,
 			"light" | "l" => Self::Light,
 			"dark" | "d" => Self::Dark,
+			"highcontrast" | "hc" => Self::HighContrast,
 			_ => return Err(InvalidTheme),
 		})
 	}
 }
 
+#[test]
+fn test_theme_flag_value() {
+	for theme in Theme::ALL {
+		assert_eq!(
+			theme.flag_value().parse::<Theme>().unwrap().flag_value(),
+			theme.flag_value()
+		);
+	}
+}
+
 #[derive(Default, Debug, Clone, Copy)]
 enum Theme {
 	Transparent,
 	Light,
 	#[default]
 	Dark,
+	HighContrast,
 }
 
 impl Theme {
+	const ALL: [Self; 4] = [
+		Self::Transparent,
+		Self::Light,
+		Self::Dark,
+		Self::HighContrast,
+	];
+
+	/// The value of the `theme` flag that picks this theme.
+	const fn flag_value(self) -> &'static str {
+		match self {
+			Self::Transparent => "transparent",
+			Self::Light => "light",
+			Self::Dark => "dark",
+			Self::HighContrast => "highcontrast",
+		}
+	}
+
 	const fn preamble(self) -> &'static str {
 		match self {
 			Self::Transparent => "",
@@ -74,6 +124,16 @@ impl Theme {
 				"#set page(fill: rgb(49, 51, 56))\n",
 				"#set text(fill: rgb(219, 222, 225))\n",
 			),
+			Self::HighContrast => concat!(
+				"#set page(fill: black)\n",
+				"#set text(fill: white, size: 13pt, weight: \"medium\")\n",
+				"#set line(stroke: 1.5pt + white)\n",
+				"#set rect(stroke: 1.5pt + white)\n",
+				"#set circle(stroke: 1.5pt + white)\n",
+				"#set ellipse(stroke: 1.5pt + white)\n",
+				"#set polygon(stroke: 1.5pt + white)\n",
+				"#set table(stroke: 1.5pt + white)\n",
+			),
 		}
 	}
 }
@@ -90,6 +150,11 @@ impl FromStr for PageSize {
 			"preview" | "p" => Self::Preview,
 			"auto" | "a" => Self::Auto,
 			"default" | "d" => Self::Default,
+			"a3" => Self::A3,
+			"a4" => Self::A4,
+			"a5" => Self::A5,
+			"letter" | "us-letter" => Self::Letter,
+			"legal" | "us-legal" => Self::Legal,
 			_ => return Err(InvalidPageSize),
 		})
 	}
@@ -101,6 +166,12 @@ enum PageSize {
 	Preview,
 	Auto,
 	Default,
+	/// Named paper sizes, with Typst's default margins.
+	A3,
+	A4,
+	A5,
+	Letter,
+	Legal,
 }
 
 impl PageSize {
@@ -109,21 +180,166 @@ impl PageSize {
 			Self::Preview => "#set page(width: 300pt, height: auto, margin: 10pt)\n",
 			Self::Auto => "#set page(width: auto, height: auto, margin: 10pt)\n",
 			Self::Default => "",
+			Self::A3 => "#set page(paper: \"a3\")\n",
+			Self::A4 => "#set page(paper: \"a4\")\n",
+			Self::A5 => "#set page(paper: \"a5\")\n",
+			Self::Letter => "#set page(paper: \"us-letter\")\n",
+			Self::Legal => "#set page(paper: \"us-legal\")\n",
+		}
+	}
+}
+
+#[derive(Debug, thiserror::Error)]
+#[error("Invalid warnings mode")]
+struct InvalidWarningsMode;
+
+impl FromStr for WarningsMode {
+	type Err = InvalidWarningsMode;
+
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		Ok(match s {
+			"inline" | "i" => Self::Inline,
+			"file" | "f" => Self::File,
+			"hidden" | "h" => Self::Hidden,
+			_ => return Err(InvalidWarningsMode),
+		})
+	}
+}
+
+/// How to show the warnings of a successful render.
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq)]
+enum WarningsMode {
+	/// In the message, unless they're too long, in which case they're attached as a file.
+	#[default]
+	Inline,
+	/// Always attached as a file.
+	File,
+	/// Not shown at all.
+	Hidden,
+}
+
+#[derive(Debug, thiserror::Error)]
+#[error("Invalid color")]
+struct InvalidColor;
+
+/// An opaque or translucent color, parsed from any CSS color syntax.
+///
+/// Displays as a Typst `rgb(...)` call, so it can be used directly in a preamble.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Color([u8; 4]);
+
+impl FromStr for Color {
+	type Err = InvalidColor;
+
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		let color = csscolorparser::parse(s).map_err(|_| InvalidColor)?;
+		Ok(Self(color.to_rgba8()))
+	}
+}
+
+impl Display for Color {
+	fn fmt(&self, formatter: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		let [r, g, b, a] = self.0;
+		if a == u8::MAX {
+			write!(formatter, "rgb({r}, {g}, {b})")
+		} else {
+			write!(formatter, "rgb({r}, {g}, {b}, {a})")
+		}
+	}
+}
+
+#[test]
+fn test_color() {
+	let parse = |s: &str| s.parse::<Color>().unwrap().to_string();
+	assert_eq!(parse("red"), "rgb(255, 0, 0)");
+	assert_eq!(parse("#31333880"), "rgb(49, 51, 56, 128)");
+	assert_eq!(parse("hsl(120, 100%, 50%)"), "rgb(0, 255, 0)");
+	assert!("notacolor".parse::<Color>().is_err());
+}
+
+#[derive(Debug, thiserror::Error)]
+#[error("Invalid length")]
+struct InvalidLength;
+
+/// A positive length, parsed from a number and a unit of `pt`, `mm`, `cm`, or `in`.
+///
+/// Displays in points, so it can be used directly in a preamble.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Length(f64);
+
+impl FromStr for Length {
+	type Err = InvalidLength;
+
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		const UNITS: &[(&str, f64)] = &[
+			("pt", 1.0),
+			("mm", 72.0 / 25.4),
+			("cm", 72.0 / 2.54),
+			("in", 72.0),
+		];
+
+		let (number, points_per_unit) = UNITS
+			.iter()
+			.find_map(|&(unit, points_per_unit)| Some((s.strip_suffix(unit)?, points_per_unit)))
+			.ok_or(InvalidLength)?;
+		let number: f64 = number.parse().map_err(|_| InvalidLength)?;
+		let points = number * points_per_unit;
+		if points.is_finite() && points > 0.0 {
+			Ok(Self(points))
+		} else {
+			Err(InvalidLength)
 		}
 	}
 }
 
+impl Display for Length {
+	fn fmt(&self, formatter: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		write!(formatter, "{}pt", self.0)
+	}
+}
+
+#[test]
+fn test_length() {
+	let parse = |s: &str| s.parse::<Length>().unwrap().to_string();
+	assert_eq!(parse("400pt"), "400pt");
+	assert_eq!(parse("2in"), "144pt");
+	assert_eq!(parse("2.5pt"), "2.5pt");
+	assert!("400".parse::<Length>().is_err());
+	assert!("-1pt".parse::<Length>().is_err());
+	assert!("infpt".parse::<Length>().is_err());
+}
+
 #[derive(Default, Debug, Clone, Copy)]
 struct Preamble {
 	page_size: PageSize,
 	theme: Theme,
+	/// Overrides the page background of the theme.
+	fill: Option<Color>,
+	/// Overrides the page height so that only the top of the document is on the first page.
+	preview_height: Option<Length>,
+	/// Turns hyphenation on or off instead of leaving it to Typst, which hyphenates justified text.
+	hyphenate: Option<bool>,
 }
 
 impl Preamble {
 	fn preamble(self) -> String {
 		let page_size = self.page_size.preamble();
 		let theme = self.theme.preamble();
-		if theme.is_empty() && page_size.is_empty() {
+		let fill = self
+			.fill
+			.map_or_else(String::new, |fill| format!("#set page(fill: {fill})\n"));
+		let preview_height = self.preview_height.map_or_else(String::new, |height| {
+			format!("#set page(height: {height})\n")
+		});
+		let hyphenate = self.hyphenate.map_or_else(String::new, |hyphenate| {
+			format!("#set text(hyphenate: {hyphenate})\n")
+		});
+		if theme.is_empty()
+			&& page_size.is_empty()
+			&& fill.is_empty()
+			&& preview_height.is_empty()
+			&& hyphenate.is_empty()
+		{
 			String::new()
 		} else {
 			format!(
@@ -131,64 +347,532 @@ impl Preamble {
 					"// Begin preamble\n",
 					"// Page size:\n",
 					"{page_size}",
+					"{preview_height}",
 					"// Theme:\n",
 					"{theme}",
+					"{fill}",
+					"// Text:\n",
+					"{hyphenate}",
 					"// End preamble\n",
 				),
 				page_size = page_size,
+				preview_height = preview_height,
 				theme = theme,
+				fill = fill,
+				hyphenate = hyphenate,
 			)
 		}
 	}
 }
 
+#[test]
+fn test_preamble_hyphenate() {
+	assert!(!Preamble::default().preamble().contains("hyphenate"));
+	let preamble = Preamble {
+		hyphenate: Some(false),
+		..Preamble::default()
+	};
+	assert!(preamble
+		.preamble()
+		.contains("#set text(hyphenate: false)\n"));
+}
+
 struct Data {
-	pool: Mutex<Worker>,
+	pool: Arc<Mutex<Worker>>,
 	database: std::sync::Mutex<Connection>,
+	/// For fetching code in `?render-url`.
+	http: reqwest::Client,
+	/// Added after render errors, such as to point to a help channel.
+	error_notice: Option<String>,
+	/// The latest render error of each user, for `?explain`.
+	last_errors: std::sync::Mutex<HashMap<UserId, String>>,
+	/// The most files attached to a render's reply, with any further images left out.
+	max_attachments: usize,
+	/// The recent renders of each user, for `?history` and `?again`.
+	history: std::sync::Mutex<History<RecentRender>>,
 }
 
+/// The flags and snippets of a render, so that it can be repeated with `?again`.
+type RecentRender = (RenderFlags, Vec<String>);
+
+/// The most users whose latest render errors are kept for `?explain`.
+/// When it's reached, they're all forgotten, which is simpler than tracking which are the oldest.
+const MAX_LAST_ERRORS: usize = 1000;
+
 type PoiseError = Box<dyn std::error::Error + Send + Sync + 'static>;
 type Context<'a> = poise::Context<'a, Data, PoiseError>;
 
-#[derive(Debug, Default)]
+// The flags are independent of each other, so they don't fit into an enum.
+#[allow(clippy::struct_excessive_bools)]
+#[derive(Debug, Default, Clone)]
 struct RenderFlags {
 	preamble: Preamble,
+	options: RenderOptions,
+	/// Render under both the light and dark themes.
+	compare: bool,
+	/// Mark the images as spoilers.
+	spoiler: bool,
+	/// Render each code block in the message on its own.
+	separate: bool,
+	/// The page, starting at 1, to attach first so that it's shown as the preview.
+	primary: Option<NonZeroUsize>,
+	warnings: WarningsMode,
+	/// Show the first image in an embed along with details about the render.
+	embed: bool,
+	/// Also attach the code, without the preambles, so that others can edit it.
+	attach_source: bool,
+	/// The description of the images for screen readers.
+	alt: Option<String>,
+	/// Caption multi-page renders with the page of each image, in order.
+	page_numbers: bool,
 }
 
-#[async_trait]
-impl<'a> poise::PopArgument<'a> for RenderFlags {
-	async fn pop_from(
-		args: &'a str,
-		attachment_index: usize,
-		ctx: &serenity::prelude::Context,
-		message: &poise::serenity_prelude::Message,
-	) -> Result<(&'a str, usize, Self), (PoiseError, Option<String>)> {
-		fn inner(raw: &HashMap<String, String>) -> Result<RenderFlags, PoiseError> {
-			let mut parsed = RenderFlags::default();
+/// Flags given as `key=value` pairs before the code block.
+trait Flags: Default {
+	/// The full names of the flags, used to suggest a flag when one is misspelled.
+	const NAMES: &'static [&'static str];
 
-			for (key, value) in raw {
-				match key.as_str() {
-					"theme" | "t" => {
-						parsed.preamble.theme = value.parse().map_err(|_| "invalid theme")?;
-					}
-					"pagesize" | "ps" => {
-						parsed.preamble.page_size = value.parse().map_err(|_| "invalid page size")?;
+	/// Applies a single flag, failing if the key is unrecognized or the value is invalid.
+	fn apply(&mut self, key: &str, value: &str) -> Result<(), PoiseError>;
+
+	/// Checks the flags together once they've all been applied,
+	/// so that combinations that can't work are rejected before anything is sent to the worker.
+	fn validate(&self) -> Result<(), PoiseError> {
+		Ok(())
+	}
+}
+
+/// The remaining arguments, and the keys and values of the flags in order.
+type ParsedFlags<'a> = (&'a str, Vec<(&'a str, String)>);
+
+/// Splits `key=value` pairs off the front of the arguments, returning them and the remaining arguments.
+///
+/// Values can be quoted to include spaces, with `\` escaping quotes and backslashes.
+/// Parsing stops at the first word that isn't a flag,
+/// so Typst code like `#let x = 1` or `` `a=b` `` is never mistaken for one.
+fn parse_flags(mut args: &str) -> Result<ParsedFlags<'_>, String> {
+	let mut flags = Vec::new();
+
+	loop {
+		let trimmed = args.trim_start();
+		let key_end = trimmed
+			.find(|ch: char| !(ch.is_ascii_alphanumeric() || ch == '_' || ch == '-'))
+			.unwrap_or(trimmed.len());
+		let key = &trimmed[..key_end];
+		let Some(after_equals) = trimmed[key_end..].strip_prefix('=') else {
+			break;
+		};
+		if !key.starts_with(|ch: char| ch.is_ascii_alphabetic()) {
+			break;
+		}
+
+		let (value, rest) = if let Some(quoted) = after_equals.strip_prefix('"') {
+			let mut value = String::new();
+			let mut chars = quoted.char_indices();
+			let end = loop {
+				match chars.next() {
+					Some((_, '\\')) => {
+						if let Some((_, escaped)) = chars.next() {
+							value.push(escaped);
+						}
 					}
-					_ => {
-						return Err(format!("unrecognized flag {key:?}").into());
+					Some((i, '"')) => break i + 1,
+					Some((_, ch)) => value.push(ch),
+					None => {
+						return Err(format!(
+							"The value of the flag `{key}` is missing its closing quote"
+						))
 					}
 				}
-			}
+			};
+			(value, &quoted[end..])
+		} else {
+			let end = after_equals
+				.find(char::is_whitespace)
+				.unwrap_or(after_equals.len());
+			(after_equals[..end].to_owned(), &after_equals[end..])
+		};
+
+		if value.is_empty() {
+			return Err(format!(
+				"The flag `{key}` is missing a value; write it like `{key}=value`, without spaces around the `=`"
+			));
+		}
+
+		flags.push((key, value));
+		args = rest;
+	}
+
+	Ok((args, flags))
+}
+
+#[test]
+fn test_parse_flags() {
+	let parse = |args| parse_flags(args).unwrap();
+	assert_eq!(
+		parse("theme=light ps=auto `code`"),
+		(
+			" `code`",
+			vec![("theme", "light".into()), ("ps", "auto".into())]
+		),
+	);
+	assert_eq!(
+		parse(r#"label="my \"fig\"" rest"#),
+		(" rest", vec![("label", r#"my "fig""#.into())]),
+	);
+	assert_eq!(parse("#let x = 1"), ("#let x = 1", vec![]));
+	assert_eq!(parse("`0..=5`"), ("`0..=5`", vec![]));
+	assert!(parse_flags("theme= `code`").is_err());
+	assert!(parse_flags(r#"label="unterminated"#).is_err());
+}
+
+/// The error for a flag that isn't in `names`, suggesting the closest one if it looks like a typo.
+fn unknown_flag(key: &str, names: &[&str]) -> PoiseError {
+	const MAX_DISTANCE: usize = 2;
+
+	let suggestion = names
+		.iter()
+		.map(|name| (strsim::levenshtein(key, name), name))
+		.filter(|&(distance, _)| distance <= MAX_DISTANCE)
+		.min_by_key(|&(distance, _)| distance);
+
+	let message = if let Some((_, name)) = suggestion {
+		format!("Unrecognized flag `{key}`; did you mean `{name}`?")
+	} else {
+		let names = names
+			.iter()
+			.map(|name| format!("`{name}`"))
+			.collect::<Vec<_>>()
+			.join(", ");
+		format!("Unrecognized flag `{key}`; the flags are {names}")
+	};
+	message.into()
+}
 
-			Ok(parsed)
+#[test]
+fn test_validate_render_flags() {
+	let flags = |raw: &[(&str, &str)]| {
+		let mut flags = RenderFlags::default();
+		for (key, value) in raw {
+			flags.apply(key, value).unwrap();
+		}
+		flags.validate()
+	};
+
+	assert!(flags(&[("tiles", "true"), ("primary", "2")]).is_ok());
+	assert!(flags(&[("tiles", "true"), ("label", "fig")]).is_err());
+	assert!(flags(&[("aspect", "16:9"), ("tiles", "true")]).is_err());
+	assert!(flags(&[("preview-height", "100pt"), ("primary", "1")]).is_ok());
+	assert!(flags(&[("preview-height", "100pt"), ("primary", "2")]).is_err());
+}
+
+#[test]
+fn test_unknown_flag() {
+	let names = ["theme", "pagesize", "label"];
+	assert_eq!(
+		unknown_flag("thme", &names).to_string(),
+		"Unrecognized flag `thme`; did you mean `theme`?",
+	);
+	assert_eq!(
+		unknown_flag("color", &names).to_string(),
+		"Unrecognized flag `color`; the flags are `theme`, `pagesize`, `label`",
+	);
+}
+
+#[allow(clippy::unused_async)] // To match `PopArgument::pop_from`.
+async fn pop_flags<'a, T: Flags>(
+	args: &'a str,
+	attachment_index: usize,
+	_ctx: &serenity::prelude::Context,
+	_message: &poise::serenity_prelude::Message,
+) -> Result<(&'a str, usize, T), (PoiseError, Option<String>)> {
+	let (remaining, raw) = parse_flags(args).map_err(|error| (error.into(), None))?;
+
+	let mut parsed = T::default();
+	for (key, value) in raw {
+		parsed.apply(key, &value).map_err(|error| (error, None))?;
+	}
+	parsed.validate().map_err(|error| (error, None))?;
+
+	Ok((remaining, attachment_index, parsed))
+}
+
+/// The longest description that Discord allows for an attachment.
+const MAX_ALT_LENGTH: usize = 1024;
+/// The widest or tallest `aspect`, as a ratio to 1, beyond which padding would make the images huge.
+const MAX_ASPECT: u32 = 8;
+
+/// Parses an aspect ratio like `16:9` or `16/9` into its width and height.
+fn parse_aspect(value: &str) -> Option<(u32, u32)> {
+	let (width, height) = value.split_once([':', '/'])?;
+	let (width, height): (u32, u32) = (width.trim().parse().ok()?, height.trim().parse().ok()?);
+	let at_most = |a: u32, b: u32| u64::from(a) <= u64::from(b) * u64::from(MAX_ASPECT);
+	(width > 0 && height > 0 && at_most(width, height) && at_most(height, width))
+		.then_some((width, height))
+}
+
+#[test]
+fn test_parse_aspect() {
+	assert_eq!(parse_aspect("16:9"), Some((16, 9)));
+	assert_eq!(parse_aspect("1/1"), Some((1, 1)));
+	assert_eq!(parse_aspect("8:1"), Some((8, 1)));
+	assert_eq!(parse_aspect("1:9"), None);
+	assert_eq!(parse_aspect("0:1"), None);
+	assert_eq!(parse_aspect("16x9"), None);
+}
+
+/// Parses a comma-separated list of output formats, like `png,svg,pdf`, ignoring repeats.
+///
+/// Raw pixels aren't offered since Discord can't show them.
+fn parse_output_formats(value: &str) -> Option<Vec<OutputFormat>> {
+	let mut formats = Vec::new();
+	for format in value.split(',') {
+		let format = match format.trim().to_ascii_lowercase().as_str() {
+			"png" => OutputFormat::Png,
+			"svg" => OutputFormat::Svg,
+			"pdf" => OutputFormat::Pdf,
+			_ => return None,
+		};
+		if !formats.contains(&format) {
+			formats.push(format);
+		}
+	}
+	Some(formats)
+}
+
+#[test]
+fn test_parse_output_formats() {
+	assert_eq!(
+		parse_output_formats("png, SVG,pdf,png"),
+		Some(vec![
+			OutputFormat::Png,
+			OutputFormat::Svg,
+			OutputFormat::Pdf
+		]),
+	);
+	assert_eq!(parse_output_formats("pdf"), Some(vec![OutputFormat::Pdf]));
+	assert_eq!(parse_output_formats("raw"), None);
+	assert_eq!(parse_output_formats(""), None);
+	assert_eq!(parse_output_formats("png,"), None);
+}
+
+/// The range of `colors`, up to the most that a palette usually has.
+const MIN_COLORS: u16 = 2;
+const MAX_COLORS: u16 = 256;
+
+/// The smallest `maxdim`, below which the images would be unreadable.
+const MIN_MAX_DIMENSION: u32 = 64;
+
+impl Flags for RenderFlags {
+	const NAMES: &'static [&'static str] = &[
+		"pagesize",
+		"theme",
+		"fill",
+		"preview-height",
+		"label",
+		"compare",
+		"srgb",
+		"seed",
+		"spoiler",
+		"warnings",
+		"nowarn",
+		"separate",
+		"primary",
+		"safe",
+		"thumbnail",
+		"tiles",
+		"embed",
+		"attachsource",
+		"alt",
+		"boxes",
+		"maxdim",
+		"strict",
+		"aspect",
+		"output",
+		"colors",
+		"maxsize",
+		"pagenumbers",
+		"hyphenate",
+	];
+
+	#[allow(clippy::too_many_lines)]
+	fn apply(&mut self, key: &str, value: &str) -> Result<(), PoiseError> {
+		match key {
+			"theme" | "t" => {
+				self.preamble.theme = value.parse().map_err(|_| "invalid theme")?;
+			}
+			"pagesize" | "ps" => {
+				self.preamble.page_size = value.parse().map_err(|_| "invalid page size")?;
+			}
+			"fill" | "f" => {
+				self.preamble.fill = Some(value.parse().map_err(|_| "invalid CSS color for fill")?);
+			}
+			"preview-height" | "ph" => {
+				self.preamble.preview_height = Some(
+					value
+						.parse()
+						.map_err(|_| "invalid length for preview-height")?,
+				);
+				self.options.max_pages = Some(1);
+			}
+			"hyphenate" => {
+				self.preamble.hyphenate = Some(value.parse().map_err(|_| "invalid bool for hyphenate")?);
+			}
+			"label" | "l" => {
+				self.options.label = Some(value.into());
+			}
+			"compare" | "c" => {
+				self.compare = value.parse().map_err(|_| "invalid bool for compare")?;
+			}
+			"srgb" => {
+				self.options.srgb = value.parse().map_err(|_| "invalid bool for srgb")?;
+			}
+			"warnings" | "w" => {
+				self.warnings = value.parse().map_err(|_| "invalid warnings mode")?;
+			}
+			"nowarn" => {
+				let hidden = value.parse().map_err(|_| "invalid bool for nowarn")?;
+				if hidden {
+					self.warnings = WarningsMode::Hidden;
+				} else if self.warnings == WarningsMode::Hidden {
+					self.warnings = WarningsMode::Inline;
+				}
+			}
+			"primary" | "p" => {
+				self.primary = Some(
+					value
+						.parse()
+						.map_err(|_| "invalid page number for primary")?,
+				);
+			}
+			"thumbnail" => {
+				self.options.thumbnail = value.parse().map_err(|_| "invalid bool for thumbnail")?;
+			}
+			"boxes" => {
+				self.options.boxes = value.parse().map_err(|_| "invalid bool for boxes")?;
+			}
+			"maxdim" => {
+				let max: u32 = value
+					.parse()
+					.ok()
+					.filter(|&max| max >= MIN_MAX_DIMENSION)
+					.ok_or_else(|| {
+						format!("maxdim must be a number of pixels, at least {MIN_MAX_DIMENSION}")
+					})?;
+				self.options.max_dimension = Some(max);
+			}
+			"tiles" => {
+				self.options.tiles = value.parse().map_err(|_| "invalid bool for tiles")?;
+			}
+			"embed" | "e" => {
+				self.embed = value.parse().map_err(|_| "invalid bool for embed")?;
+			}
+			"alt" => {
+				if value.chars().count() > MAX_ALT_LENGTH {
+					return Err(format!("alt text can be at most {MAX_ALT_LENGTH} characters").into());
+				}
+				self.alt = Some(value.into());
+			}
+			"attachsource" => {
+				self.attach_source = value.parse().map_err(|_| "invalid bool for attachsource")?;
+			}
+			"strict" => {
+				self.options.strict = value.parse().map_err(|_| "invalid bool for strict")?;
+			}
+			"safe" => {
+				self.options.safe = value.parse().map_err(|_| "invalid bool for safe")?;
+			}
+			"separate" | "s" => {
+				self.separate = value.parse().map_err(|_| "invalid bool for separate")?;
+			}
+			"spoiler" => {
+				self.spoiler = value.parse().map_err(|_| "invalid bool for spoiler")?;
+			}
+			"pagenumbers" | "pn" => {
+				self.page_numbers = value.parse().map_err(|_| "invalid bool for pagenumbers")?;
+			}
+			"aspect" => {
+				let ratio = parse_aspect(value).ok_or_else(|| {
+					format!("invalid aspect ratio; use a ratio like 16:9, at most {MAX_ASPECT}:1 either way")
+				})?;
+				self.options.aspect = Some(ratio);
+			}
+			"maxsize" => {
+				let Length(max_size) = value.parse().map_err(|_| "invalid length for maxsize")?;
+				// The worker clamps it to its own maximum, which is far within `f32`'s range.
+				#[allow(clippy::cast_possible_truncation)]
+				let max_size = max_size as f32;
+				self.options.max_size = Some(max_size);
+			}
+			"colors" => {
+				let colors = value
+					.parse()
+					.ok()
+					.filter(|colors| (MIN_COLORS..=MAX_COLORS).contains(colors))
+					.ok_or_else(|| format!("colors must be a number from {MIN_COLORS} to {MAX_COLORS}"))?;
+				self.options.colors = Some(colors);
+			}
+			"output" => {
+				self.options.formats = parse_output_formats(value)
+					.ok_or("invalid output formats; use a comma-separated list of png, svg, and pdf")?;
+			}
+			"seed" => {
+				self.options.seed = Some(value.parse().map_err(|_| "invalid integer for seed")?);
+			}
+			_ => {
+				return Err(unknown_flag(key, Self::NAMES));
+			}
 		}
 
-		let (remaining, pos, raw) =
-			poise::prefix_argument::KeyValueArgs::pop_from(args, attachment_index, ctx, message).await?;
+		Ok(())
+	}
+
+	fn validate(&self) -> Result<(), PoiseError> {
+		if self.options.label.is_some() && self.options.tiles {
+			return Err(
+				"tiles can't be used with label, since the labelled element is cropped out".into(),
+			);
+		}
+		if self.options.aspect.is_some() && self.options.tiles {
+			return Err(
+				"aspect can't be used with tiles, since each tile would be cropped or padded".into(),
+			);
+		}
+		if self.spoiler && self.options.thumbnail {
+			return Err(
+				"thumbnail can't be used with spoiler, since the thumbnail would show the page unhidden"
+					.into(),
+			);
+		}
+		if self.spoiler && self.embed {
+			return Err(
+				"embed can't be used with spoiler, since the embed would show the first image unhidden"
+					.into(),
+			);
+		}
+		if let (Some(primary), Some(max_pages)) = (self.primary, self.options.max_pages) {
+			if primary.get() > max_pages {
+				return Err(
+					format!(
+						"primary can't be more than {max_pages} with preview-height, which limits the pages"
+					)
+					.into(),
+				);
+			}
+		}
+		Ok(())
+	}
+}
 
-		inner(&raw.0)
-			.map(|parsed| (remaining, pos, parsed))
-			.map_err(|error| (error, None))
+#[async_trait]
+impl<'a> poise::PopArgument<'a> for RenderFlags {
+	async fn pop_from(
+		args: &'a str,
+		attachment_index: usize,
+		ctx: &serenity::prelude::Context,
+		message: &poise::serenity_prelude::Message,
+	) -> Result<(&'a str, usize, Self), (PoiseError, Option<String>)> {
+		pop_flags(args, attachment_index, ctx, message).await
 	}
 }
 
@@ -199,13 +883,65 @@ fn render_help() -> String {
 		"\
 Render the given code as an image.
 
-Syntax: `?render [pagesize=<page size>] [theme=<theme>] <code block> [...]`
+Syntax: `?render [pagesize=<page size>] [theme=<theme>] [fill=<color>] [preview-height=<length>] [label=<label>] [compare=<bool>] [srgb=<bool>] [seed=<integer>] [spoiler=<bool>] [warnings=<mode>] [nowarn=<bool>] [separate=<bool>] [primary=<page>] [safe=<bool>] [thumbnail=<bool>] [tiles=<bool>] [embed=<bool>] [attachsource=<bool>] [alt=<text>] [boxes=<bool>] [maxdim=<pixels>] [strict=<bool>] [aspect=<ratio>] [output=<formats>] [colors=<count>] [maxsize=<length>] [pagenumbers=<bool>] [hyphenate=<bool>] <code block> [...]`
 
 **Flags**
 
-- `pagesize` can be `preview` (default),  `auto`, or `default`.
+- `pagesize` can be `preview` (default),  `auto`, `default`, or a paper size: `a3`, `a4`, `a5`, `letter`, or `legal`.
+
+- `theme` can be `dark` (default), `light`, `transparent`, or `highcontrast` (white on black with larger text and heavier strokes). Use `?theme-preview` to see each of them.
+
+- `fill` sets the page background to any CSS color, such as `white`, `#fef3c7`, or `hsl(200, 50%, 20%)`, overriding the theme's background.
+
+- `preview-height` shows only the top of the document by making the pages this tall and rendering just the first, such as `preview-height=400pt`. The units `pt`, `mm`, `cm`, and `in` can be used.
+
+- `label` crops the output to the element with the given label, e.g. `label=fig` for `<fig>`.
+
+- `compare` can be `true` to render under both the light and dark themes, ignoring `theme`.
+
+- `srgb` can be `true` to tag the images with the sRGB color profile, for color-critical work.
+
+- `seed` makes randomness reproducible: the seed is available as `sys.inputs.seed`, and `datetime.today()` is pinned to 1970-01-01. Typst itself has no random number generator, so packages must be given the seed explicitly.
+
+- `spoiler` can be `true` to hide the images until they're clicked, such as for exercise solutions.
+
+- `warnings` can be `inline` (default), `file` to attach the warnings as a text file, or `hidden` to not show them. Long inline warnings are attached instead.
+
+- `nowarn` can be `true` to not show the warnings, the same as `warnings=hidden`.
+
+- `separate` can be `true` to render each code block in the message on its own, labelled \"Snippet 1\", \"Snippet 2\", and so on. At most {MAX_SNIPPETS} code blocks are allowed.
+
+- `primary` is a page number to attach first, so that it's shown as the preview. The other pages are still attached.
+
+- `safe` can be `true` to forbid packages, so only the standard library is available.
+
+- `thumbnail` can be `true` to also show a small preview of the first page, for quickly scanning large documents.
 
-- `theme` can be `dark` (default), `light`, or `transparent`.
+- `tiles` can be `true` to split pages that are too big to render into several smaller images, instead of failing. The pieces are numbered left to right, then top to bottom.
+
+- `embed` can be `true` to show the first image in an embed along with the number of pages and warnings and how long the render took.
+
+- `attachsource` can be `true` to also attach the code as a `.typ` file, so that others can download and edit it. The preambles are not included.
+
+- `alt` describes the images for screen readers, such as `alt=\"A plot of sin(x)\"`. Quote it to include spaces.
+
+- `boxes` can be `true` to outline the bounds of every box in the layout in red, for debugging spacing and alignment.
+
+- `maxdim` scales the images down so that neither side is more than this many pixels, such as `maxdim=1200`, to keep them small on slow connections. At least {MIN_MAX_DIMENSION} pixels.
+
+- `strict` can be `true` to fail with the warnings instead of rendering if there are any, for keeping documents free of warnings.
+
+- `aspect` fits the images to an aspect ratio, such as `aspect=16:9` for social previews. Space around the content is cropped if that's enough; otherwise the images are padded with the background.
+
+- `output` is a comma-separated list of the formats to attach: `png` (default) for images of the pages, `svg` for an SVG of each page, and `pdf` for a PDF of the whole document, such as `output=png,svg,pdf`. The code is compiled only once for all of them.
+
+- `colors` reduces the images to at most this many colors, from {MIN_COLORS} to {MAX_COLORS}, such as `colors=16` for a pixel-art look. The colors are chosen to suit each image. Full color by default.
+
+- `maxsize` fails the render if a page is wider or taller than this, or splits the page with `tiles`, such as `maxsize=2000pt`, to catch accidentally huge output early. It can't be more than the limit shown by `?limits`, which is the default.
+
+- `pagenumbers` can be `true` to caption renders of more than one page with the page of each image, in order, such as \"Pages: 1/3, 2/3, 3/3\".
+
+- `hyphenate` can be `true` or `false` to turn hyphenation on or off with `#set text(hyphenate: ...)` in the preamble. By default, Typst hyphenates only justified text.
 
 To be clear, the full default preamble is:
 
@@ -213,7 +949,12 @@ To be clear, the full default preamble is:
 {default_preamble}
 ```
 
+To see the preamble for other flags, use `?preamble` with them, such as `?preamble theme=light pagesize=a4`.
 To remove the preamble entirely, use `pagesize=default theme=transparent`.
+Server managers can also disable it for the whole server with `?toggle-preamble`, in which case `pagesize` and `theme` have no effect.
+They can also add their own preamble with `?set-guild-preamble`, which comes after the automatic one.
+
+For trying out examples, every document can read two bundled files: `example.png`, a small landscape picture, and `example.csv`, a table of fruits with their colors and prices, such as with `#image(\"example.png\")` or `#csv(\"example.csv\")`.
 
 **Examples**
 
@@ -229,15 +970,25 @@ And some text.
 ``‍`
 
 ?render `#myfunc()` I don't understand this code, can anyone help?
-```"
+
+?render $x^2$
+```
+
+Without a code block, the rest of the message is rendered as-is, so there is no room for a trailing comment.
+When replying to a message, `?render` with no code renders the code in the replied-to message instead.
+Since flags come first, bare code can't start with something that looks like a flag, such as `a=b`; use a code block in that case."
 	)
 }
 
 /// Extracts the contents of a code block.
 ///
 /// If the language is `ansi`, then ANSI escape codes will be stripped from the input.
+///
+/// If there is no code block at all, the rest of the message is taken as the code, unmodified.
 struct CodeBlock {
 	source: String,
+	/// The language given after the opening backticks, if any.
+	language: Option<String>,
 }
 
 #[async_trait]
@@ -250,10 +1001,40 @@ impl<'a> poise::PopArgument<'a> for CodeBlock {
 	) -> Result<(&'a str, usize, Self), (PoiseError, Option<String>)> {
 		if let Some(code_block_start) = args.find("```") {
 			args = &args[code_block_start..];
+		} else if !args.trim_start().starts_with('`') && !args.trim().is_empty() {
+			let source = args.trim().to_owned();
+			let code_block = CodeBlock {
+				source,
+				language: None,
+			};
+			return Ok(("", attachment_index, code_block));
 		}
 
 		let (rest, attachment_index, code_block) =
-			poise::prefix_argument::CodeBlock::pop_from(args, attachment_index, ctx, message).await?;
+			match poise::prefix_argument::CodeBlock::pop_from(args, attachment_index, ctx, message).await
+			{
+				Ok(popped) => popped,
+				Err(error) => {
+					// Poise rejects empty code blocks, but commands can give a clearer error for them
+					// than for a missing code block.
+					let empty = args
+						.strip_prefix("```")
+						.and_then(|inner| inner.split_once("```"))
+						.filter(|(inner, _)| {
+							// Ignore the language, if any.
+							let code = inner.split_once('\n').map_or(*inner, |(_, code)| code);
+							code.trim().is_empty()
+						});
+					let Some((_, rest)) = empty else {
+						return Err(error);
+					};
+					let code_block = CodeBlock {
+						source: String::new(),
+						language: None,
+					};
+					return Ok((rest, attachment_index, code_block));
+				}
+			};
 
 		let mut source = code_block.code;
 		// Strip ANSI escapes if provided.
@@ -270,7 +1051,73 @@ impl<'a> poise::PopArgument<'a> for CodeBlock {
 			.replace(&pattern, replacement)
 			.replace(&pattern, replacement);
 
-		Ok((rest, attachment_index, CodeBlock { source }))
+		let code_block = CodeBlock {
+			source,
+			language: code_block.language,
+		};
+		Ok((rest, attachment_index, code_block))
+	}
+}
+
+/// The code for `?render`, which is like [`CodeBlock`]
+/// except that if there's nothing at all and the message is a reply, the code block in the replied-to message is used.
+struct RenderCode(CodeBlock);
+
+#[async_trait]
+impl<'a> poise::PopArgument<'a> for RenderCode {
+	async fn pop_from(
+		args: &'a str,
+		attachment_index: usize,
+		ctx: &serenity::prelude::Context,
+		message: &poise::serenity_prelude::Message,
+	) -> Result<(&'a str, usize, Self), (PoiseError, Option<String>)> {
+		if args.trim().is_empty() {
+			if let Some(referenced) = &message.referenced_message {
+				// Only code counts, since a message without any is most likely just conversation.
+				if let Some(code_start) = referenced.content.find('`') {
+					let referenced_args = &referenced.content[code_start..];
+					if let Ok((_, _, code_block)) =
+						CodeBlock::pop_from(referenced_args, 0, ctx, referenced).await
+					{
+						return Ok((args, attachment_index, Self(code_block)));
+					}
+				}
+			}
+		}
+
+		let (rest, attachment_index, code_block) =
+			CodeBlock::pop_from(args, attachment_index, ctx, message).await?;
+		Ok((rest, attachment_index, Self(code_block)))
+	}
+}
+
+/// The code blocks after the first, for rendering snippets separately.
+///
+/// Anything else is ignored, as with [`Rest`].
+struct ExtraCodeBlocks(Vec<String>);
+
+#[async_trait]
+impl<'a> poise::PopArgument<'a> for ExtraCodeBlocks {
+	async fn pop_from(
+		mut args: &'a str,
+		mut attachment_index: usize,
+		ctx: &serenity::prelude::Context,
+		message: &poise::serenity_prelude::Message,
+	) -> Result<(&'a str, usize, Self), (PoiseError, Option<String>)> {
+		let mut blocks = Vec::new();
+		// Only fenced code blocks count, so inline code in a trailing comment isn't rendered.
+		while args.contains("```") {
+			let Ok((rest, index, block)) =
+				CodeBlock::pop_from(args, attachment_index, ctx, message).await
+			else {
+				break;
+			};
+			blocks.push(block.source);
+			args = rest;
+			attachment_index = index;
+		}
+
+		Ok(("", attachment_index, Self(blocks)))
 	}
 }
 
@@ -288,162 +1135,1983 @@ impl<'a> poise::PopArgument<'a> for Rest {
 	}
 }
 
-/// Render Typst code as an image.
-#[poise::command(
-	prefix_command,
-	track_edits,
-	broadcast_typing,
-	user_cooldown = 1,
-	help_text_fn = "render_help",
-	aliases("r")
-)]
-async fn render(
-	ctx: Context<'_>,
-	#[description = "Flags"] flags: RenderFlags,
-	#[description = "Code to render"] code: CodeBlock,
-	#[rename = "rest"]
-	#[description = "Extra message content"]
-	_: Rest,
-) -> Result<(), PoiseError> {
-	let pool = &ctx.data().pool;
+/// Describes the amount of a download, as a percentage if the total is known.
+fn describe_downloaded(downloaded: u64, total: Option<u64>) -> String {
+	match total {
+		Some(total) if total > 0 => format!("{}%", downloaded.min(total) * 100 / total),
+		_ => format!("{} KiB", downloaded / 1024),
+	}
+}
+
+#[test]
+fn test_describe_downloaded() {
+	assert_eq!(describe_downloaded(512, Some(2048)), "25%");
+	assert_eq!(describe_downloaded(4096, Some(2048)), "100%");
+	assert_eq!(describe_downloaded(3000, None), "2 KiB");
+	assert_eq!(describe_downloaded(3000, Some(0)), "2 KiB");
+}
+
+fn describe_progress(locale: Locale, progress: &Progress) -> String {
+	match progress {
+		Progress::DownloadingPackage { spec } => locale.downloading(spec),
+		Progress::DownloadedBytes { downloaded, total } => describe_downloaded(*downloaded, *total),
+		Progress::Compiling => locale.compiling().into(),
+		Progress::Rendering { page, total } => locale.rendering(*page, *total),
+	}
+}
 
-	let mut source = code.source;
-	source.insert_str(0, &flags.preamble.preamble());
+/// Renders the source on the worker, posting progress messages in the meantime.
+///
+/// To avoid noise, progress is only shown once a package download starts,
+/// since that's what can take a noticeable amount of time.
+///
+/// If the worker crashed and the render only succeeded on retry, the crash is returned as well.
+/// The time taken is only that of the worker, not of waiting for it to be free.
+async fn render_with_progress(
+	ctx: Context<'_>,
+	input: RenderInput,
+	options: RenderOptions,
+) -> anyhow::Result<(Rendered, Option<anyhow::Error>, Duration)> {
+	let pool = &ctx.data().pool;
+	let locale = guild_locale(ctx).map_err(|error| anyhow::anyhow!(error))?;
 
-	let mut progress = String::new();
+	// Each download, with how much of it has been downloaded if that's been reported.
+	let mut downloads = Vec::<(String, Option<String>)>::new();
+	let mut handle = None;
 	let (progress_send, mut progress_recv) = mpsc::channel(4);
-	let (res, ()) = {
+	let ((res, elapsed), ()) = {
 		let mut pool = pool.lock().await;
-		join!(pool.render(source, progress_send), async {
+		let render = async {
+			let start = Instant::now();
+			let res = pool.render(input, options, progress_send).await;
+			(res, start.elapsed())
+		};
+		join!(render, async {
 			// When `render` finishes, it will drop the sender so this loop will finish.
 			while let Some(item) = progress_recv.recv().await {
-				progress.reserve(item.len() + 1);
-				progress.push_str(&item);
-				progress.push('\n');
-				let message = format!("Progress: ```ansi\n{}\n```", sanitize_code_block(&progress));
-				_ = ctx.say(message).await;
+				let status = describe_progress(locale, &item);
+				match item {
+					Progress::DownloadingPackage { .. } => downloads.push((status.clone(), None)),
+					// The amount is always for the latest download.
+					Progress::DownloadedBytes { .. } => {
+						if let Some((_, amount)) = downloads.last_mut() {
+							*amount = Some(status.clone());
+						}
+					}
+					_ => {}
+				}
+				if downloads.is_empty() {
+					continue;
+				}
+
+				let mut progress = downloads
+					.iter()
+					.map(|(download, amount)| match amount {
+						Some(amount) => format!("{download} ({amount})"),
+						None => download.clone(),
+					})
+					.collect::<Vec<_>>()
+					.join("\n");
+				if !matches!(
+					item,
+					Progress::DownloadingPackage { .. } | Progress::DownloadedBytes { .. }
+				) {
+					progress.push('\n');
+					progress.push_str(&status);
+				}
+				let message = format!(
+					"{}: ```ansi\n{}\n```",
+					locale.progress(),
+					sanitize_code_block(&progress),
+				);
+				match &handle {
+					None => handle = ctx.say(message).await.ok(),
+					Some(handle) => {
+						_ = handle
+							.edit(ctx, CreateReply::default().content(message))
+							.await;
+					}
+				}
 			}
 		})
 	};
 
-	match res {
-		Ok(res) => {
-			let mut message = CreateReply::default().reply(true);
+	res.map(|(rendered, crash)| (rendered, crash, elapsed))
+}
+
+/// Adds the text to the content as a code block, or attaches it as a file if it's too long or `as_file` is set.
+///
+/// ANSI escapes are stripped from the attached file since it won't be highlighted.
+fn add_long_text(
+	mut message: CreateReply,
+	content: &mut String,
+	heading: &str,
+	text: &str,
+	file_name: &str,
+	as_file: bool,
+) -> CreateReply {
+	/// Leaves room for the rest of the message within Discord's limit of 2000 characters.
+	const MAX_INLINE_LENGTH: usize = 1500;
+
+	if as_file || text.len() > MAX_INLINE_LENGTH {
+		writeln!(content, "{heading} (see `{file_name}`)").unwrap();
+		let text = strip_ansi_escapes::strip_str(text);
+		message = message.attachment(CreateAttachment::bytes(text, file_name));
+	} else {
+		writeln!(
+			content,
+			"{heading}:\n```ansi\n{}\n```",
+			sanitize_code_block(text)
+		)
+		.unwrap();
+	}
+
+	message
+}
+
+/// Adds notes about anything unusual in the render, each starting with the label.
+fn add_notes(content: &mut String, locale: Locale, label: &str, res: &Rendered) {
+	let note = locale.note();
+
+	if res.images.is_empty() && res.exports.is_empty() {
+		writeln!(content, "{label}{note}: {}", locale.no_pages()).unwrap();
+	}
+
+	if res.more_pages > 0 {
+		writeln!(
+			content,
+			"{label}{note}: {}",
+			locale.more_pages_ignored(res.more_pages),
+		)
+		.unwrap();
+	}
+
+	if let Some(page) = res.timed_out_page {
+		writeln!(content, "{label}{note}: {}", locale.page_timed_out(page)).unwrap();
+	}
+
+	if res
+		.encodings
+		.iter()
+		.any(|encoding| matches!(encoding, Encoding::Jpeg { .. }))
+	{
+		writeln!(content, "{label}{note}: {}", locale.compressed_as_jpeg()).unwrap();
+	}
+}
+
+/// Adds the notes and images of a successful render to the reply.
+///
+/// If `name` is given, it's used to distinguish this render from others in the same reply.
+/// Images that would take the reply past `max_attachments` files are left out, with a note.
+#[allow(clippy::too_many_arguments)]
+fn add_rendered(
+	mut message: CreateReply,
+	content: &mut String,
+	locale: Locale,
+	max_attachments: usize,
+	name: Option<&str>,
+	flags: &RenderFlags,
+	res: Rendered,
+	elapsed: Duration,
+) -> CreateReply {
+	let label = name.map_or_else(String::new, |name| format!("{name}: "));
+	let note = locale.note();
+
+	add_notes(content, locale, &label, &res);
+
+	let mut file_prefix = name.map_or_else(String::new, |name| {
+		// Turn names like "Snippet 1, Light" into "snippet-1-light".
+		let slug = name
+			.to_lowercase()
+			.split(|ch: char| !ch.is_alphanumeric())
+			.filter(|part| !part.is_empty())
+			.collect::<Vec<_>>()
+			.join("-");
+		format!("{slug}-")
+	});
+
+	if !res.warnings.is_empty() && flags.warnings != WarningsMode::Hidden {
+		message = add_long_text(
+			message,
+			content,
+			&format!("{label}{}", locale.succeeded_with_warnings()),
+			&res.warnings,
+			&format!("{file_prefix}warnings.txt"),
+			flags.warnings == WarningsMode::File,
+		);
+	}
+
+	if flags.spoiler {
+		// Discord hides attachments whose names start with this until they're clicked.
+		file_prefix.insert_str(0, "SPOILER_");
+	}
+	let mut images = image_names(res.images.len(), &res.tiled)
+		.into_iter()
+		.zip(res.images.into_iter().zip(res.encodings))
+		.map(|((page, name), (image, encoding))| {
+			let name = format!("{name}.{}", extension(encoding));
+			((page, name), image)
+		})
+		.collect::<Vec<_>>();
+	if let Some(primary) = flags.primary {
+		let primary = primary.get();
+		if images.iter().any(|&((page, _), _)| page == primary) {
+			// The sort is stable, so the tiles of the page stay in order.
+			images.sort_by_key(|&((page, _), _)| page != primary);
+		} else {
+			writeln!(
+				content,
+				"{label}{note}: {}",
+				locale.primary_not_rendered(primary)
+			)
+			.unwrap();
+		}
+	}
+	if let Some(thumbnail) = res.thumbnail {
+		let file_name = format!("{file_prefix}thumbnail.png");
+		let embed = CreateEmbed::new()
+			.title(name.unwrap_or("Preview"))
+			.thumbnail(format!("attachment://{file_name}"));
+		message = message
+			.attachment(CreateAttachment::bytes(thumbnail, file_name))
+			.embed(embed);
+	}
+
+	// Counted before any images are left out, which doesn't change how many pages there are.
+	let page_count = images.iter().map(|&((page, _), _)| page).max().unwrap_or(0) + res.more_pages;
+
+	let mut exports = res.exports;
+	let room = max_attachments.saturating_sub(message.attachments.len());
+	let omitted = (images.len() + exports.len()).saturating_sub(room);
+	if omitted > 0 {
+		images.truncate(room);
+		exports.truncate(room - images.len());
+		let omitted = locale.attachments_omitted(omitted, max_attachments);
+		writeln!(content, "{label}{note}: {omitted}").unwrap();
+	}
+
+	let pages = images.iter().map(|&((page, _), _)| page);
+	if let Some(captions) = page_captions(pages, page_count).filter(|_| flags.page_numbers) {
+		writeln!(content, "{label}{}: {captions}", locale.pages()).unwrap();
+	}
+
+	if flags.embed {
+		if let Some(((_, first_name), _)) = images.first() {
+			let embed = CreateEmbed::new()
+				.title(name.unwrap_or("Render"))
+				.field("Pages", page_count.to_string(), true)
+				.field("Warnings", res.warning_count.to_string(), true)
+				.field("Time", format!("{:.2}s", elapsed.as_secs_f64()), true)
+				.image(format!("attachment://{file_prefix}{first_name}"));
+			message = message.embed(embed);
+		}
+	}
+
+	for ((_, name), image) in images {
+		let mut image = CreateAttachment::bytes(image, format!("{file_prefix}{name}"));
+		if let Some(alt) = &flags.alt {
+			image = image.description(alt);
+		}
+		message = message.attachment(image);
+	}
+
+	for export in exports {
+		let name = export
+			.page
+			.map_or_else(|| "document".to_owned(), |page| format!("page-{page}"));
+		let name = format!("{file_prefix}{name}.{}", export_extension(export.format));
+		message = message.attachment(CreateAttachment::bytes(export.data, name));
+	}
+
+	message
+}
+
+/// Lists the page of each image out of the total, like `1/3, 2/3, 3/3`, if the document has more than one page.
+fn page_captions(pages: impl Iterator<Item = usize>, total: usize) -> Option<String> {
+	let captions = pages
+		.map(|page| format!("{page}/{total}"))
+		.collect::<Vec<_>>();
+	(total > 1 && !captions.is_empty()).then(|| captions.join(", "))
+}
+
+#[test]
+fn test_page_captions() {
+	assert_eq!(
+		page_captions([2, 1, 3].into_iter(), 5).as_deref(),
+		Some("2/5, 1/5, 3/5")
+	);
+	assert_eq!(page_captions([1].into_iter(), 1), None);
+	assert_eq!(page_captions([].into_iter(), 2), None);
+}
+
+fn extension(encoding: Encoding) -> &'static str {
+	match encoding {
+		Encoding::Jpeg { .. } => "jpg",
+		Encoding::Png | Encoding::Raw => "png",
+	}
+}
+
+fn export_extension(format: OutputFormat) -> &'static str {
+	match format {
+		OutputFormat::Png | OutputFormat::Raw => "png",
+		OutputFormat::Svg => "svg",
+		OutputFormat::Pdf => "pdf",
+	}
+}
+
+/// Names the images by their page number, and by their tile number for pages that were split into
+/// tiles. The page numbers start at 1 and are returned along with the names.
+fn image_names(count: usize, tiled: &[TiledPage]) -> Vec<(usize, String)> {
+	let mut names = Vec::with_capacity(count);
+	let mut page = 0;
+	while names.len() < count {
+		page += 1;
+		if let Some(tiled) = tiled.iter().find(|tiled| tiled.first_image == names.len()) {
+			for tile in 1..=tiled.columns * tiled.rows {
+				names.push((page, format!("page-{page}-tile-{tile}")));
+			}
+		} else {
+			names.push((page, format!("page-{page}")));
+		}
+	}
+	names
+}
+
+#[test]
+fn test_image_names() {
+	let tiled = [TiledPage {
+		first_image: 1,
+		columns: 2,
+		rows: 1,
+	}];
+	let names = image_names(4, &tiled);
+	assert_eq!(
+		names,
+		[
+			(1, "page-1".to_owned()),
+			(2, "page-2-tile-1".to_owned()),
+			(2, "page-2-tile-2".to_owned()),
+			(3, "page-3".to_owned()),
+		],
+	);
+	assert!(image_names(0, &[]).is_empty());
+}
+
+/// Whether Discord rejected the request without acting on it, so sending it again won't duplicate anything.
+fn is_transient_send_error(error: &serenity::Error) -> bool {
+	let serenity::Error::Http(error) = error else {
+		return false;
+	};
+	// 429 is a rate limit and 502-504 mean the request never reached Discord's API.
+	error
+		.status_code()
+		.is_some_and(|status| matches!(status.as_u16(), 429 | 502..=504))
+}
+
+/// Sends the reply, retrying after transient failures so that a completed render isn't lost.
+async fn send_with_retry(ctx: Context<'_>, message: CreateReply) -> Result<(), serenity::Error> {
+	const RETRIES: u32 = 2;
+	const DELAY: Duration = Duration::from_secs(2);
+
+	let mut retries_left = RETRIES;
+	loop {
+		match ctx.send(message.clone()).await {
+			Ok(_) => return Ok(()),
+			Err(error) if retries_left > 0 && is_transient_send_error(&error) => {
+				tracing::warn!(?error, "Transient error while sending reply, retrying");
+				retries_left -= 1;
+				tokio::time::sleep(DELAY).await;
+			}
+			Err(error) => return Err(error),
+		}
+	}
+}
+
+/// Renders the input with the default options and adds it to the reply, or the error if it failed.
+///
+/// For commands that render something of their own rather than the user's snippets.
+async fn add_render(
+	ctx: Context<'_>,
+	message: CreateReply,
+	content: &mut String,
+	locale: Locale,
+	name: Option<&str>,
+	flags: &RenderFlags,
+	input: RenderInput,
+) -> CreateReply {
+	match render_with_progress(ctx, input, RenderOptions::default()).await {
+		Ok((res, _, elapsed)) => add_rendered(
+			message,
+			content,
+			locale,
+			ctx.data().max_attachments,
+			name,
+			flags,
+			res,
+			elapsed,
+		),
+		Err(error) => {
+			let label = name.map_or_else(String::new, |name| format!("{name}: "));
+			writeln!(
+				content,
+				"{label}{}:\n```ansi\n{}\n```",
+				locale.error_occurred(),
+				sanitize_code_block(&format!("{error:?}")),
+			)
+			.unwrap();
+			message
+		}
+	}
+}
+
+/// Sends a reply built with [`add_render`].
+async fn send_rendered(
+	ctx: Context<'_>,
+	mut message: CreateReply,
+	content: String,
+) -> Result<(), PoiseError> {
+	if !content.is_empty() {
+		message = message.content(content);
+	}
+	send_with_retry(ctx, message).await?;
+	Ok(())
+}
+
+/// Whether the guild has disabled the automatic preamble with `?toggle-preamble`.
+fn preamble_disabled(ctx: Context<'_>) -> Result<bool, PoiseError> {
+	let Some(guild_id) = ctx.guild_id() else {
+		return Ok(false);
+	};
+
+	let disabled = ctx
+		.data()
+		.database
+		.lock()
+		.map_err(|_| "db mutex poisoned, oops")?
+		.prepare("select 1 from preamble_disabled where guild = :guild")?
+		.exists(named_params!(":guild": guild_id.get()))?;
+
+	Ok(disabled)
+}
+
+/// The preamble set for the guild with `?set-guild-preamble`, if any.
+fn guild_preamble(ctx: Context<'_>) -> Result<Option<String>, PoiseError> {
+	let Some(guild_id) = ctx.guild_id() else {
+		return Ok(None);
+	};
+
+	let preamble = ctx
+		.data()
+		.database
+		.lock()
+		.map_err(|_| "db mutex poisoned, oops")?
+		.prepare("select preamble from guild_preambles where guild = :guild")?
+		.query(named_params!(":guild": guild_id.get()))?
+		.next()?
+		.map(|row| row.get::<_, String>("preamble"))
+		.transpose()?;
+
+	Ok(preamble)
+}
+
+/// The language set for the guild with `?set-language`, or English.
+fn guild_locale(ctx: Context<'_>) -> Result<Locale, PoiseError> {
+	let Some(guild_id) = ctx.guild_id() else {
+		return Ok(Locale::default());
+	};
+
+	let code = ctx
+		.data()
+		.database
+		.lock()
+		.map_err(|_| "db mutex poisoned, oops")?
+		.prepare("select locale from guild_locales where guild = :guild")?
+		.query(named_params!(":guild": guild_id.get()))?
+		.next()?
+		.map(|row| row.get::<_, String>("locale"))
+		.transpose()?;
+
+	Ok(
+		code
+			.as_deref()
+			.and_then(Locale::from_code)
+			.unwrap_or_default(),
+	)
+}
+
+/// Increments the author's render count, which is shown in the leaderboard.
+///
+/// Edits of an existing invocation are not counted again.
+fn count_render(ctx: Context<'_>) -> Result<(), PoiseError> {
+	if let poise::Context::Prefix(prefix) = ctx {
+		if !matches!(prefix.trigger, poise::MessageDispatchTrigger::MessageCreate) {
+			return Ok(());
+		}
+	}
+
+	let Some(guild_id) = ctx.guild_id() else {
+		return Ok(());
+	};
+
+	ctx
+		.data()
+		.database
+		.lock()
+		.map_err(|_| "db mutex poisoned, oops")?
+		.execute(
+			"insert into render_counts (user, guild, count) values (:user, :guild, 1) on conflict do update set count = count + 1",
+			named_params!(":user": ctx.author().id.get(), ":guild": guild_id.get()),
+		)?;
+
+	Ok(())
+}
+
+/// The most code blocks that can be rendered at once with `separate=true`.
+const MAX_SNIPPETS: usize = 4;
+
+/// Checks the snippets before sending them to the worker, so problems can be reported precisely.
+fn check_snippets(snippets: &[String]) -> Result<(), String> {
+	if let Some(i) = snippets
+		.iter()
+		.position(|snippet| snippet.trim().is_empty())
+	{
+		return Err(if snippets.len() == 1 {
+			"Your code block is empty".into()
+		} else {
+			format!("Code block {} is empty", i + 1)
+		});
+	}
+
+	if snippets.len() > MAX_SNIPPETS {
+		return Err(format!(
+			"Too many code blocks: at most {MAX_SNIPPETS} can be rendered separately, but there are {}",
+			snippets.len(),
+		));
+	}
+
+	Ok(())
+}
+
+/// Prepends the guild's preamble and then the automatic preamble, if any, to the source.
+fn with_preambles(
+	mut source: String,
+	preamble: Option<Preamble>,
+	guild_preamble: Option<&str>,
+) -> String {
+	if let Some(guild_preamble) = guild_preamble {
+		source.insert(0, '\n');
+		source.insert_str(0, guild_preamble);
+	}
+	if let Some(preamble) = preamble {
+		source.insert_str(0, &preamble.preamble());
+	}
+	source
+}
+
+/// Render Typst code as an image.
+#[poise::command(
+	prefix_command,
+	track_edits,
+	broadcast_typing,
+	user_cooldown = 1,
+	help_text_fn = "render_help",
+	aliases("r")
+)]
+async fn render(
+	ctx: Context<'_>,
+	#[description = "Flags"] flags: RenderFlags,
+	#[description = "Code to render"] code: RenderCode,
+	#[rename = "rest"]
+	#[description = "Extra message content"]
+	extra: ExtraCodeBlocks,
+) -> Result<(), PoiseError> {
+	let snippets = if flags.separate {
+		std::iter::once(code.0.source).chain(extra.0).collect()
+	} else {
+		vec![code.0.source]
+	};
+	render_snippets(ctx, &flags, snippets).await
+}
+
+/// The preambles to render each snippet with, named if there's more than one.
+fn variants(flags: &RenderFlags) -> Vec<(Option<&'static str>, Preamble)> {
+	if flags.compare {
+		vec![
+			(
+				Some("Light"),
+				Preamble {
+					theme: Theme::Light,
+					..flags.preamble
+				},
+			),
+			(
+				Some("Dark"),
+				Preamble {
+					theme: Theme::Dark,
+					..flags.preamble
+				},
+			),
+		]
+	} else {
+		vec![(None, flags.preamble)]
+	}
+}
+
+/// Renders each snippet under each variant requested by the flags and replies with the results.
+async fn render_snippets(
+	ctx: Context<'_>,
+	flags: &RenderFlags,
+	snippets: Vec<String>,
+) -> Result<(), PoiseError> {
+	if let Err(message) = check_snippets(&snippets) {
+		ctx.reply(message).await?;
+		return Ok(());
+	}
+
+	let message = loop {
+		match render_all(ctx, flags, &snippets).await? {
+			Ok(message) => break message,
+			Err((error, true)) => {
+				if !offer_retry(ctx, error).await? {
+					return Ok(());
+				}
+			}
+			Err((error, false)) => {
+				ctx.reply(error).await?;
+				return Ok(());
+			}
+		}
+	};
+
+	send_with_retry(ctx, message).await?;
+
+	count_render(ctx)?;
+	ctx
+		.data()
+		.history
+		.lock()
+		.map_err(|_| "history mutex poisoned, oops")?
+		.record(ctx.author().id, (flags.clone(), snippets), Instant::now());
+
+	Ok(())
+}
+
+/// How long the retry button on a failed render can be clicked.
+const RETRY_TIMEOUT: Duration = Duration::from_mins(5);
+
+/// Replies with the error and a button to retry the render,
+/// returning whether the author clicked it before [`RETRY_TIMEOUT`].
+async fn offer_retry(ctx: Context<'_>, error: String) -> Result<bool, PoiseError> {
+	let button = CreateButton::new("retry")
+		.label("Retry")
+		.style(ButtonStyle::Secondary);
+	let reply = CreateReply::default()
+		.content(error)
+		.reply(true)
+		.components(vec![CreateActionRow::Buttons(vec![button])]);
+	let handle = ctx.send(reply).await?;
+	let message_id = handle.message().await?.id;
+
+	let press = ComponentInteractionCollector::new(ctx)
+		.message_id(message_id)
+		.author_id(ctx.author().id)
+		.timeout(RETRY_TIMEOUT)
+		.await;
+
+	// The button is removed either way, so it can't be clicked again.
+	let Some(press) = press else {
+		_ = handle
+			.edit(ctx, CreateReply::default().components(Vec::new()))
+			.await;
+		return Ok(false);
+	};
+	press
+		.create_response(
+			ctx,
+			CreateInteractionResponse::UpdateMessage(
+				CreateInteractionResponseMessage::new().components(Vec::new()),
+			),
+		)
+		.await?;
+
+	Ok(true)
+}
+
+/// Renders every snippet under every variant, returning the reply,
+/// or the error message for the first render that failed and whether it's worth retrying.
+async fn render_all(
+	ctx: Context<'_>,
+	flags: &RenderFlags,
+	snippets: &[String],
+) -> Result<Result<CreateReply, (String, bool)>, PoiseError> {
+	let variants = variants(flags);
+
+	let use_preamble = !preamble_disabled(ctx)?;
+	let guild_preamble = guild_preamble(ctx)?;
+	let locale = guild_locale(ctx)?;
 
-			let mut content = String::new();
+	let mut message = CreateReply::default().reply(true);
+	let mut content = String::new();
+
+	let mut renders = Vec::new();
+	for (i, snippet) in snippets.iter().enumerate() {
+		for &(variant, preamble) in &variants {
+			let name = match (flags.separate, variant) {
+				(false, variant) => variant.map(String::from),
+				(true, None) => Some(format!("Snippet {}", i + 1)),
+				(true, Some(variant)) => Some(format!("Snippet {}, {variant}", i + 1)),
+			};
+			renders.push((name, preamble, snippet));
+		}
+	}
+
+	// Attached before the images so that they count against `max_attachments` too.
+	if flags.attach_source {
+		for (i, snippet) in snippets.iter().enumerate() {
+			let file_name = if flags.separate {
+				format!("snippet-{}-source.typ", i + 1)
+			} else {
+				"source.typ".into()
+			};
+			message = message.attachment(CreateAttachment::bytes(snippet.as_str(), file_name));
+		}
+	}
+
+	let mut options = flags.options.clone();
+	// When the message is edited, the worker can reuse its work on the previous version.
+	// This only makes sense for a single render, since others would replace its source.
+	if let (1, poise::Context::Prefix(prefix)) = (renders.len(), ctx) {
+		options.session = Some(prefix.msg.id.get());
+	}
+
+	for (name, preamble, snippet) in renders {
+		let source = with_preambles(
+			snippet.clone(),
+			use_preamble.then_some(preamble),
+			guild_preamble.as_deref(),
+		);
+
+		match render_with_progress(ctx, RenderInput::Code(source), options.clone()).await {
+			Ok((res, crash, elapsed)) => {
+				if let Some(crash) = crash {
+					let label = name
+						.as_deref()
+						.map_or_else(String::new, |name| format!("{name}: "));
+					writeln!(
+						content,
+						"{label}{}: {} ({crash:#})",
+						locale.note(),
+						locale.worker_restarted(),
+					)
+					.unwrap();
+				}
+				message = add_rendered(
+					message,
+					&mut content,
+					locale,
+					ctx.data().max_attachments,
+					name.as_deref(),
+					flags,
+					res,
+					elapsed,
+				);
+			}
+			Err(error) => {
+				// Only a crash or timeout might go differently the next time.
+				let retryable = error.downcast_ref::<WorkerFailure>().is_some();
+				let error = format!("{error:?}");
+				record_error(ctx, error.clone())?;
+				let label = name.map_or_else(String::new, |name| format!("{name}: "));
+				let mut message = format!(
+					"{label}{}:\n```ansi\n{}\n```",
+					locale.error_occurred(),
+					sanitize_code_block(&error),
+				);
+				if let Some(notice) = &ctx.data().error_notice {
+					write!(message, "\n{notice}").unwrap();
+				}
+				return Ok(Err((message, retryable)));
+			}
+		}
+	}
+
+	if !content.is_empty() {
+		message = message.content(content);
+	}
+
+	Ok(Ok(message))
+}
+
+/// Sites that `?render-url` can fetch from, all of which serve raw text.
+///
+/// Only these are allowed so that the bot can't be used to make requests to arbitrary addresses, such as internal ones.
+const URL_HOSTS: &[&str] = &[
+	"gist.githubusercontent.com",
+	"raw.githubusercontent.com",
+	"pastebin.com",
+	"paste.rs",
+];
+/// The largest code that `?render-url` will fetch, in bytes.
+const MAX_FETCH_BYTES: usize = 64 * 1024;
+const FETCH_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Fetches the code at the URL, which must be on one of [`URL_HOSTS`].
+async fn fetch_source(http: &reqwest::Client, url: &str) -> Result<String, String> {
+	// Discord doesn't embed URLs surrounded by angle brackets, so people may write them like that.
+	let url = url.trim_start_matches('<').trim_end_matches('>');
+	let mut url = reqwest::Url::parse(url).map_err(|error| format!("Invalid URL: {error}"))?;
+
+	let host = url.host_str().unwrap_or_default();
+	if url.scheme() != "https" || !URL_HOSTS.contains(&host) {
+		let hosts = URL_HOSTS
+			.iter()
+			.map(|host| format!("`{host}`"))
+			.collect::<Vec<_>>()
+			.join(", ");
+		return Err(format!(
+			"Only `https` URLs on these sites are supported: {hosts}"
+		));
+	}
+	// Pastebin serves a web page rather than the paste's text unless the URL is under `/raw`.
+	if host == "pastebin.com" && !url.path().starts_with("/raw/") {
+		let path = format!("/raw{}", url.path());
+		url.set_path(&path);
+	}
+
+	let fetch_error = |error: reqwest::Error| format!("Couldn't fetch the code: {error}");
+	let mut response = http.get(url).send().await.map_err(fetch_error)?;
+	let status = response.status();
+	if !status.is_success() {
+		return Err(format!(
+			"Couldn't fetch the code: the site responded with {status}"
+		));
+	}
+
+	let too_big = || format!("The code is too big; the limit is {MAX_FETCH_BYTES} bytes");
+	if response
+		.content_length()
+		.is_some_and(|length| length > MAX_FETCH_BYTES as u64)
+	{
+		return Err(too_big());
+	}
+	// The length header can't be trusted, so check the length of what's actually received.
+	let mut body = Vec::new();
+	while let Some(chunk) = response.chunk().await.map_err(fetch_error)? {
+		body.extend_from_slice(&chunk);
+		if body.len() > MAX_FETCH_BYTES {
+			return Err(too_big());
+		}
+	}
+
+	String::from_utf8(body).map_err(|_| "The fetched code isn't valid UTF-8".to_owned())
+}
+
+/// Fetch Typst code from a paste site and render it.
+///
+/// Syntax: `?render-url [flags...] <url>`
+///
+/// The flags are the same as for `?render`. \
+/// The URL must point to the raw text on a supported site: GitHub gists (the "Raw" link), `raw.githubusercontent.com`, Pastebin, or `paste.rs`. \
+/// The code can be at most 64 KiB.
+///
+/// **Examples**
+///
+/// ```
+/// ?render-url https://pastebin.com/raw/abcd1234
+///
+/// ?render-url theme=light <https://gist.githubusercontent.com/user/id/raw/document.typ>
+/// ```
+#[poise::command(
+	prefix_command,
+	rename = "render-url",
+	track_edits,
+	broadcast_typing,
+	user_cooldown = 1
+)]
+async fn render_url(
+	ctx: Context<'_>,
+	#[description = "Flags"] flags: RenderFlags,
+	#[description = "URL of the code"] url: String,
+	#[rename = "rest"]
+	#[description = "Extra message content"]
+	_: Rest,
+) -> Result<(), PoiseError> {
+	let source = match fetch_source(&ctx.data().http, &url).await {
+		Ok(source) => source,
+		Err(message) => {
+			ctx.reply(message).await?;
+			return Ok(());
+		}
+	};
+
+	render_snippets(ctx, &flags, vec![source]).await
+}
+
+/// Numbers each line of raw blocks in a gutter to the left.
+const LINE_NUMBERS_PREAMBLE: &str = concat!(
+	"#show raw.line: it => {\n",
+	"  box(width: 2em, align(right, text(fill: gray, str(it.number))))\n",
+	"  h(1em)\n",
+	"  it.body\n",
+	"}\n",
+);
+
+/// Render code as a syntax-highlighted image with line numbers, without running it.
+///
+/// Syntax: `?code <code block> [...]`
+///
+/// The code block's language is used for highlighting, defaulting to Typst. \
+/// This is for sharing code as an image; use `?render` to see what Typst code produces.
+///
+/// **Examples**
+///
+/// ```
+/// ?code ``‍`rust
+/// fn main() {
+///     println!("Hello, world!");
+/// }
+/// ``‍`
+/// ```
+#[poise::command(prefix_command, track_edits, broadcast_typing, user_cooldown = 1)]
+async fn code(
+	ctx: Context<'_>,
+	#[description = "Code to highlight"] code: CodeBlock,
+	#[rename = "rest"]
+	#[description = "Extra message content"]
+	_: Rest,
+) -> Result<(), PoiseError> {
+	if let Err(message) = check_snippets(std::slice::from_ref(&code.source)) {
+		ctx.reply(message).await?;
+		return Ok(());
+	}
+
+	let preamble = Preamble {
+		page_size: PageSize::Auto,
+		// Typst's highlighting colors are meant for a light background.
+		theme: Theme::Light,
+		..Preamble::default()
+	};
+	let language = code.language.as_deref().unwrap_or("typ");
+	let source = format!(
+		"{}{LINE_NUMBERS_PREAMBLE}#raw(block: true, lang: {}, {})\n",
+		preamble.preamble(),
+		string_literal(language),
+		string_literal(&code.source),
+	);
+
+	let locale = guild_locale(ctx)?;
+	let mut content = String::new();
+	let message = add_render(
+		ctx,
+		CreateReply::default().reply(true),
+		&mut content,
+		locale,
+		None,
+		&RenderFlags::default(),
+		RenderInput::Code(source),
+	)
+	.await;
+	send_rendered(ctx, message, content).await
+}
+
+/// The sample rendered by `?theme-preview`, with the kinds of content that themes style differently.
+const THEME_SAMPLE: &str = concat!(
+	"= Heading\n",
+	"Body text with *strong* and _emphasized_ words, and a #link(\"https://typst.app\")[link].\n",
+	"$ sum_(k=1)^n k = (n(n+1)) / 2 $\n",
+	"#box(stroke: 1pt, inset: 5pt)[A box] #rect(width: 30pt, height: 10pt) #line(length: 40pt)\n",
+);
+
+/// Render a fixed sample under each theme, to help pick one for the `theme` flag of `?render`.
+///
+/// Syntax: `?theme-preview`
+#[poise::command(
+	prefix_command,
+	slash_command,
+	rename = "theme-preview",
+	broadcast_typing,
+	user_cooldown = 5
+)]
+async fn theme_preview(ctx: Context<'_>) -> Result<(), PoiseError> {
+	let locale = guild_locale(ctx)?;
+	let mut message = CreateReply::default().reply(true);
+	let mut content = String::new();
+
+	for theme in Theme::ALL {
+		let name = theme.flag_value();
+		let preamble = Preamble {
+			theme,
+			..Preamble::default()
+		};
+		let source = format!("{}{THEME_SAMPLE}", preamble.preamble());
+		let flags = RenderFlags {
+			alt: Some(format!("The sample under the {name} theme")),
+			..RenderFlags::default()
+		};
+
+		writeln!(content, "{name}: `theme={name}`").unwrap();
+		message = add_render(
+			ctx,
+			message,
+			&mut content,
+			locale,
+			Some(name),
+			&flags,
+			RenderInput::Code(source),
+		)
+		.await;
+	}
+
+	send_rendered(ctx, message, content).await
+}
+
+/// Render two versions of some code and highlight where they differ.
+///
+/// Syntax: `?diff <code block> <code block> [...]`
+///
+/// Only the first page of each is compared. Unchanged pixels are faded and changed pixels are shown in magenta. \
+/// If the pages have different sizes, the area covered by only one of them counts as changed. \
+/// The preamble is added as with `?render`, using the default theme and page size.
+///
+/// **Examples**
+///
+/// ```
+/// ?diff `#set text(red); Hello` `#set text(blue); Hello`
+/// ```
+#[poise::command(prefix_command, track_edits, broadcast_typing, user_cooldown = 1)]
+async fn diff(
+	ctx: Context<'_>,
+	#[description = "Code before the change"] before: CodeBlock,
+	#[description = "Code after the change"] after: CodeBlock,
+	#[rename = "rest"]
+	#[description = "Extra message content"]
+	_: Rest,
+) -> Result<(), PoiseError> {
+	let snippets = [before.source, after.source];
+	if let Err(message) = check_snippets(&snippets) {
+		ctx.reply(message).await?;
+		return Ok(());
+	}
+
+	let use_preamble = !preamble_disabled(ctx)?;
+	let guild_preamble = guild_preamble(ctx)?;
+	let [before, after] = snippets.map(|snippet| {
+		with_preambles(
+			snippet,
+			use_preamble.then(Preamble::default),
+			guild_preamble.as_deref(),
+		)
+	});
+
+	let pool = &ctx.data().pool;
+
+	let res = pool.lock().await.diff(before, after).await;
+
+	let diff = match res {
+		Ok(diff) => diff,
+		Err(error) => {
+			let message = format!(
+				"{}:\n```ansi\n{}\n```",
+				guild_locale(ctx)?.error_occurred(),
+				sanitize_code_block(&format!("{error:?}")),
+			);
+			ctx.reply(message).await?;
+			return Ok(());
+		}
+	};
+
+	let mut content = if diff.changed_pixels == 0 {
+		"The renders are identical.".to_owned()
+	} else {
+		format!("{} pixels differ.", diff.changed_pixels)
+	};
+	if let Some(((before_width, before_height), (after_width, after_height))) = diff.size_mismatch {
+		write!(
+			content,
+			" The pages have different sizes ({before_width}×{before_height} and {after_width}×{after_height} pixels).",
+		)
+		.unwrap();
+	}
+
+	let message = CreateReply::default()
+		.reply(true)
+		.content(content)
+		.attachment(CreateAttachment::bytes(diff.image, "diff.png"));
+	send_with_retry(ctx, message).await?;
+
+	Ok(())
+}
+
+/// Show this menu.
+#[poise::command(prefix_command, track_edits, slash_command)]
+async fn help(
+	ctx: Context<'_>,
+	#[description = "Specific command to show help about"] command: Option<String>,
+) -> Result<(), PoiseError> {
+	let config = poise::builtins::HelpConfiguration {
+		extra_text_at_bottom: "\
+Type ?help command for more info on a command.
+You can edit your message to the bot and the bot will edit its response.
+Commands prefixed with / can be used as slash commands and prefix commands.
+Commands prefixed with ? can only be used as prefix commands.
+The bot is written by mattf_. Feel free to reach out in the Typst Discord if you have any questions.
+",
+		..Default::default()
+	};
+	poise::builtins::help(ctx, command.as_deref(), config).await?;
+	Ok(())
+}
+
+/// Get a link to the bot's source.
+#[poise::command(prefix_command, slash_command)]
+async fn source(ctx: Context<'_>) -> Result<(), PoiseError> {
+	ctx.reply(format!("<{SOURCE_URL}>")).await?;
+	Ok(())
+}
+
+/// Remembers the render error so that the author can use `?explain` on it.
+fn record_error(ctx: Context<'_>, error: String) -> Result<(), PoiseError> {
+	let mut last_errors = ctx
+		.data()
+		.last_errors
+		.lock()
+		.map_err(|_| "last errors mutex poisoned, oops")?;
+	let author = ctx.author().id;
+	if last_errors.len() >= MAX_LAST_ERRORS && !last_errors.contains_key(&author) {
+		last_errors.clear();
+	}
+	last_errors.insert(author, error);
+	Ok(())
+}
+
+/// Explain your latest render error in plain language.
+///
+/// Syntax: `?explain`
+///
+/// Only common errors have explanations.
+#[poise::command(prefix_command, slash_command)]
+async fn explain(ctx: Context<'_>) -> Result<(), PoiseError> {
+	let error = ctx
+		.data()
+		.last_errors
+		.lock()
+		.map_err(|_| "last errors mutex poisoned, oops")?
+		.get(&ctx.author().id)
+		.cloned();
+	let Some(error) = error else {
+		ctx.reply("You haven't had a render error recently").await?;
+		return Ok(());
+	};
+
+	let explanations = explanations::find(&error);
+	let message = if explanations.is_empty() {
+		"There's no explanation for that error yet. Try asking for help, including your code and the error.".into()
+	} else {
+		explanations.join("\n\n")
+	};
+	ctx.reply(message).await?;
+
+	Ok(())
+}
+
+/// The most characters of a snippet shown in `?history`.
+const HISTORY_PREVIEW_LENGTH: usize = 60;
+
+/// The first line of the snippet, shortened for `?history`.
+fn snippet_preview(snippet: &str) -> String {
+	let line = snippet.trim().lines().next().unwrap_or_default();
+	let mut preview: String = line
+		.chars()
+		.take(HISTORY_PREVIEW_LENGTH)
+		// Backticks would end the inline code that the preview is shown in.
+		.map(|ch| if ch == '`' { '\'' } else { ch })
+		.collect();
+	if preview.len() < line.len() || snippet.trim().lines().nth(1).is_some() {
+		preview.push('…');
+	}
+	preview
+}
+
+#[test]
+fn test_snippet_preview() {
+	assert_eq!(snippet_preview("  hello  "), "hello");
+	assert_eq!(snippet_preview("#let x = `a`\nx"), "#let x = 'a'…");
+	assert_eq!(
+		snippet_preview(&"a".repeat(100)),
+		format!("{}…", "a".repeat(60))
+	);
+}
+
+/// List your recent renders.
+///
+/// Syntax: `?history`
+///
+/// Renders are kept for an hour. Use `?again` with an ID to render one again.
+#[poise::command(prefix_command, slash_command)]
+async fn history(ctx: Context<'_>) -> Result<(), PoiseError> {
+	let now = Instant::now();
+	let lines = ctx
+		.data()
+		.history
+		.lock()
+		.map_err(|_| "history mutex poisoned, oops")?
+		.entries(ctx.author().id, now)
+		.map(|entry| {
+			let minutes = now.duration_since(entry.time).as_secs() / 60;
+			let preview = entry
+				.render
+				.1
+				.first()
+				.map(|snippet| snippet_preview(snippet))
+				.unwrap_or_default();
+			format!("`{}` ({minutes} min ago): `{preview}`", entry.id)
+		})
+		.collect::<Vec<_>>();
+
+	let content = if lines.is_empty() {
+		format!(
+			"You haven't rendered anything in the last {} minutes",
+			history::MAX_AGE.as_secs() / 60,
+		)
+	} else {
+		lines.into_iter().rev().collect::<Vec<_>>().join("\n")
+	};
+	ctx
+		.send(
+			CreateReply::default()
+				.content(content)
+				.reply(true)
+				.ephemeral(true),
+		)
+		.await?;
+
+	Ok(())
+}
+
+/// Render one of your recent renders again.
+///
+/// Syntax: `?again <id>`
+///
+/// The IDs are listed by `?history`. The render uses the same flags as before.
+///
+/// **Examples**
+///
+/// ```
+/// ?again 3
+/// ```
+#[poise::command(prefix_command, slash_command)]
+async fn again(
+	ctx: Context<'_>,
+	#[description = "The ID of the render, from `?history`"] id: u64,
+) -> Result<(), PoiseError> {
+	let render = ctx
+		.data()
+		.history
+		.lock()
+		.map_err(|_| "history mutex poisoned, oops")?
+		.get(ctx.author().id, id, Instant::now())
+		.cloned();
+	let Some((flags, snippets)) = render else {
+		ctx
+			.reply("You have no recent render with that ID; see `?history`")
+			.await?;
+		return Ok(());
+	};
+
+	render_snippets(ctx, &flags, snippets).await
+}
+
+/// Render an example of a standard library function.
+///
+/// Syntax: `?example [function]`
+///
+/// Without a function, the functions with examples are listed.
+///
+/// **Examples**
+///
+/// ```
+/// ?example table
+/// ```
+#[poise::command(prefix_command, slash_command, track_edits)]
+async fn example(
+	ctx: Context<'_>,
+	#[description = "The function to show an example of"] function: Option<String>,
+) -> Result<(), PoiseError> {
+	let Some(function) = function else {
+		let names = examples::EXAMPLES
+			.iter()
+			.map(|(name, _)| format!("`{name}`"))
+			.collect::<Vec<_>>()
+			.join(", ");
+		ctx
+			.reply(format!("Examples are available for: {names}"))
+			.await?;
+		return Ok(());
+	};
+
+	let Some(code) = examples::find(&function) else {
+		let message = format!("There is no example for {function:?}; use `?example` to list them");
+		ctx.reply(message).await?;
+		return Ok(());
+	};
+
+	let mut source = code.to_owned();
+	source.insert_str(0, &Preamble::default().preamble());
+
+	let locale = guild_locale(ctx)?;
+	let mut content = format!("```typ\n{}\n```\n", sanitize_code_block(code));
+	let message = add_render(
+		ctx,
+		CreateReply::default().reply(true),
+		&mut content,
+		locale,
+		None,
+		&RenderFlags::default(),
+		RenderInput::Code(source),
+	)
+	.await;
+	send_rendered(ctx, message, content).await
+}
+
+/// Turns a package given as `name@version` or `name:version` into a `@preview` package specification.
+///
+/// Full specifications like `@preview/name:version` are kept as they are.
+fn template_spec(package: &str) -> Option<String> {
+	if package.starts_with('@') {
+		return Some(package.into());
+	}
+	let (name, version) = package.split_once(['@', ':'])?;
+	Some(format!("@preview/{name}:{version}"))
+}
+
+#[test]
+fn test_template_spec() {
+	assert_eq!(
+		template_spec("charged-ieee@0.1.3").as_deref(),
+		Some("@preview/charged-ieee:0.1.3"),
+	);
+	assert_eq!(
+		template_spec("charged-ieee:0.1.3").as_deref(),
+		Some("@preview/charged-ieee:0.1.3"),
+	);
+	assert_eq!(
+		template_spec("@local/mine:1.0.0").as_deref(),
+		Some("@local/mine:1.0.0"),
+	);
+	assert_eq!(template_spec("charged-ieee"), None);
+}
+
+/// Render a template from Typst Universe.
+///
+/// Syntax: `?template <package>@<version>`
+///
+/// The template is rendered as it is when starting a new project from it,
+/// without the preamble, so it looks like it would in the web app.
+///
+/// **Examples**
+///
+/// ```
+/// ?template charged-ieee@0.1.3
+/// ```
+#[poise::command(prefix_command, slash_command, track_edits)]
+async fn template(
+	ctx: Context<'_>,
+	#[description = "The template package, like charged-ieee@0.1.3"] package: String,
+) -> Result<(), PoiseError> {
+	let Some(spec) = template_spec(&package) else {
+		ctx
+			.reply("The package needs a version, like `charged-ieee@0.1.3`")
+			.await?;
+		return Ok(());
+	};
+
+	let locale = guild_locale(ctx)?;
+	let mut content = String::new();
+	let message = add_render(
+		ctx,
+		CreateReply::default().reply(true),
+		&mut content,
+		locale,
+		None,
+		&RenderFlags::default(),
+		RenderInput::Template(spec),
+	)
+	.await;
+	send_rendered(ctx, message, content).await
+}
+
+#[derive(Debug, Default)]
+struct AstFlags {
+	spans: bool,
+}
+
+impl Flags for AstFlags {
+	const NAMES: &'static [&'static str] = &["spans"];
+
+	fn apply(&mut self, key: &str, value: &str) -> Result<(), PoiseError> {
+		match key {
+			"spans" | "s" => {
+				self.spans = value.parse().map_err(|_| "invalid bool for spans")?;
+			}
+			_ => {
+				return Err(unknown_flag(key, Self::NAMES));
+			}
+		}
+
+		Ok(())
+	}
+}
+
+#[async_trait]
+impl<'a> poise::PopArgument<'a> for AstFlags {
+	async fn pop_from(
+		args: &'a str,
+		attachment_index: usize,
+		ctx: &serenity::prelude::Context,
+		message: &poise::serenity_prelude::Message,
+	) -> Result<(&'a str, usize, Self), (PoiseError, Option<String>)> {
+		pop_flags(args, attachment_index, ctx, message).await
+	}
+}
+
+/// Get the AST for the given code.
+///
+/// Syntax: `?ast [spans=<bool>] <code block> [...]`
+///
+/// With `spans=true`, each node is annotated with the line and column where it starts.
+///
+/// ASTs too long for a message are attached as a file and posted in pieces in a thread.
+///
+/// **Examples**
+///
+/// ```
+/// ?ast `hello, world!`
+///
+/// ?ast ``‍`
+/// = Heading!
+///
+/// And some text.
+///
+/// #lorem(100)
+/// ``‍`
+///
+/// ?ast `#((3): 4)` Interesting parse result here.
+///
+/// ?ast spans=true `#let x = 1`
+/// ```
+#[poise::command(prefix_command, track_edits, broadcast_typing)]
+async fn ast(
+	ctx: Context<'_>,
+	#[description = "Flags"] flags: AstFlags,
+	#[description = "Code to parse"] code: CodeBlock,
+	#[rename = "rest"]
+	#[description = "Extra message content"]
+	_: Rest,
+) -> Result<(), PoiseError> {
+	let pool = &ctx.data().pool;
+
+	let res = pool.lock().await.ast(code.source, flags.spans).await;
+
+	match res {
+		Ok(ast) => {
+			let message = format!("```ansi\n{}```", sanitize_code_block(&ast));
+			if message.chars().count() <= MAX_MESSAGE_LENGTH {
+				ctx.reply(message).await?;
+			} else {
+				send_long_ast(ctx, &ast).await?;
+			}
+		}
+		Err(error) => {
+			let message = format!(
+				"{}:\n```ansi\n{}```",
+				guild_locale(ctx)?.error_occurred(),
+				sanitize_code_block(&format!("{error:?}")),
+			);
+			ctx.reply(message).await?;
+		}
+	}
+
+	Ok(())
+}
+
+/// Discord's limit on the length of a message, in characters.
+const MAX_MESSAGE_LENGTH: usize = 2000;
+
+/// Splits the text into chunks of at most `max_length` characters, breaking between lines where possible.
+fn chunk_lines(text: &str, max_length: usize) -> Vec<String> {
+	let mut chunks = Vec::new();
+	let mut chunk = String::new();
+	let mut chunk_length = 0;
+
+	for line in text.split_inclusive('\n') {
+		// Start a new chunk rather than splitting the line, unless it's too long for any chunk.
+		if chunk_length + line.chars().count() > max_length && !chunk.is_empty() {
+			chunks.push(std::mem::take(&mut chunk));
+			chunk_length = 0;
+		}
+		for ch in line.chars() {
+			if chunk_length == max_length {
+				chunks.push(std::mem::take(&mut chunk));
+				chunk_length = 0;
+			}
+			chunk.push(ch);
+			chunk_length += 1;
+		}
+	}
+
+	if !chunk.is_empty() {
+		chunks.push(chunk);
+	}
+	chunks
+}
+
+#[test]
+fn test_chunk_lines() {
+	assert_eq!(chunk_lines("ab\ncd\nef", 6), ["ab\ncd\n", "ef"]);
+	assert_eq!(chunk_lines("ab\ncdefgh\ni", 4), ["ab\n", "cdef", "gh\ni"]);
+	assert!(chunk_lines("", 4).is_empty());
+}
+
+/// Attaches an AST that's too long for a message, and posts it in pieces in a thread on the reply
+/// so it can be read without downloading it.
+///
+/// The thread is skipped outside of servers, for very long ASTs, and if the bot can't create threads.
+async fn send_long_ast(ctx: Context<'_>, ast: &str) -> Result<(), PoiseError> {
+	/// Leaves room for the code block around each piece.
+	const CHUNK_LENGTH: usize = MAX_MESSAGE_LENGTH - 100;
+	/// The most messages to post in the thread.
+	const MAX_CHUNKS: usize = 10;
+
+	let chunks = chunk_lines(ast, CHUNK_LENGTH);
+	let use_thread = ctx.guild_id().is_some() && chunks.len() <= MAX_CHUNKS;
+
+	let note = if use_thread {
+		"The AST is too long for one message, so it's attached and posted in pieces in the thread."
+	} else {
+		"The AST is too long for one message, so it's attached."
+	};
+	let reply = CreateReply::default()
+		.reply(true)
+		.content(note)
+		.attachment(CreateAttachment::bytes(
+			strip_ansi_escapes::strip_str(ast),
+			"ast.txt",
+		));
+	let handle = ctx.send(reply).await?;
+
+	if !use_thread {
+		return Ok(());
+	}
+
+	let message = handle.message().await?;
+	let thread = match ctx
+		.channel_id()
+		.create_thread_from_message(ctx, message.id, CreateThread::new("AST"))
+		.await
+	{
+		Ok(thread) => thread,
+		Err(error) => {
+			// The attachment is enough, such as when the bot lacks permission to create threads.
+			tracing::warn!(?error, "Couldn't create a thread for the AST");
+			return Ok(());
+		}
+	};
+	for chunk in chunks {
+		let chunk = format!("```ansi\n{}```", sanitize_code_block(&chunk));
+		thread.id.say(ctx, chunk).await?;
+	}
+
+	Ok(())
+}
+
+/// Show the AST and the compiler's errors and warnings for the given code.
+///
+/// Syntax: `?debug <code block> [...]`
+///
+/// No preamble is added, and the AST is truncated; use `?ast` to see all of it.
+///
+/// **Examples**
+///
+/// ```
+/// ?debug `#let x = (1, 2`
+/// ```
+#[poise::command(prefix_command, track_edits, broadcast_typing)]
+async fn debug(
+	ctx: Context<'_>,
+	#[description = "Code to check"] code: CodeBlock,
+	#[rename = "rest"]
+	#[description = "Extra message content"]
+	_: Rest,
+) -> Result<(), PoiseError> {
+	/// Leaves room for the diagnostics within Discord's message length limit.
+	const MAX_AST_LENGTH: usize = 400;
+
+	let pool = &ctx.data().pool;
+
+	let res = pool.lock().await.debug(code.source).await;
+
+	let info = match res {
+		Ok(info) => info,
+		Err(error) => {
+			let message = format!(
+				"{}:\n```ansi\n{}```",
+				guild_locale(ctx)?.error_occurred(),
+				sanitize_code_block(&format!("{error:?}")),
+			);
+			ctx.reply(message).await?;
+			return Ok(());
+		}
+	};
+
+	let mut ast = info.ast;
+	if ast.len() > MAX_AST_LENGTH {
+		let end = ast.floor_char_boundary(MAX_AST_LENGTH);
+		ast.truncate(end);
+		ast.push_str("\n...");
+	}
+	let mut content = format!("**AST:**\n```ansi\n{}\n```\n", sanitize_code_block(&ast));
+
+	let mut message = CreateReply::default().reply(true);
+	if info.diagnostics.is_empty() {
+		content.push_str("**Diagnostics:** none");
+	} else {
+		message = add_long_text(
+			message,
+			&mut content,
+			"**Diagnostics**",
+			&info.diagnostics,
+			"diagnostics.txt",
+			false,
+		);
+	}
+
+	ctx.send(message.content(content)).await?;
+
+	Ok(())
+}
+
+#[derive(Debug, Default)]
+struct TextWidthFlags {
+	font: Option<String>,
+	size: Option<Length>,
+}
+
+impl Flags for TextWidthFlags {
+	const NAMES: &'static [&'static str] = &["font", "size"];
+
+	fn apply(&mut self, key: &str, value: &str) -> Result<(), PoiseError> {
+		match key {
+			"font" | "f" => {
+				self.font = Some(value.into());
+			}
+			"size" | "s" => {
+				self.size = Some(value.parse().map_err(|_| "invalid length for size")?);
+			}
+			_ => {
+				return Err(unknown_flag(key, Self::NAMES));
+			}
+		}
+
+		Ok(())
+	}
+}
+
+#[async_trait]
+impl<'a> poise::PopArgument<'a> for TextWidthFlags {
+	async fn pop_from(
+		args: &'a str,
+		attachment_index: usize,
+		ctx: &serenity::prelude::Context,
+		message: &poise::serenity_prelude::Message,
+	) -> Result<(&'a str, usize, Self), (PoiseError, Option<String>)> {
+		pop_flags(args, attachment_index, ctx, message).await
+	}
+}
+
+/// Measure how wide and tall some text is when set in a font.
+///
+/// Syntax: `?textwidth [font=<font>] [size=<length>] <text>`
+///
+/// The text is measured exactly as written, not as Typst markup.
+/// Without `font` and `size`, Typst's defaults are used.
+/// The height is from the top of the capital letters on the first line to the baseline of the last.
+///
+/// **Examples**
+///
+/// ```
+/// ?textwidth font="New Computer Modern" size=12pt Hello, world!
+/// ```
+#[poise::command(prefix_command, track_edits, broadcast_typing)]
+async fn textwidth(
+	ctx: Context<'_>,
+	#[description = "Flags"] flags: TextWidthFlags,
+	#[rest]
+	#[description = "Text to measure"]
+	text: String,
+) -> Result<(), PoiseError> {
+	let pool = &ctx.data().pool;
 
-			if res.images.is_empty() {
-				writeln!(content, "Note: no pages generated").unwrap();
-			}
+	let size = flags.size.map(|size| size.0);
+	let res = pool.lock().await.measure(text, flags.font, size).await;
 
-			if res.more_pages > 0 {
-				let more_pages = res.more_pages;
-				writeln!(
-					content,
-					"Note: {more_pages} more page{s} ignored",
-					s = if more_pages == 1 { "" } else { "s" },
-				)
-				.unwrap();
-			}
+	let message = match res {
+		Ok(size) => format!(
+			"**Width:** {:.2}pt\n**Height:** {:.2}pt",
+			size.width, size.height,
+		),
+		Err(error) => format!(
+			"{}:\n```ansi\n{}\n```",
+			guild_locale(ctx)?.error_occurred(),
+			sanitize_code_block(&format!("{error:?}")),
+		),
+	};
+	ctx.reply(message).await?;
 
-			if !res.warnings.is_empty() {
-				writeln!(
-					content,
-					"Render succeeded with warnings:\n```ansi\n{}\n```",
-					sanitize_code_block(&res.warnings),
-				)
-				.unwrap();
-			}
+	Ok(())
+}
 
-			if !content.is_empty() {
-				message = message.content(content);
-			}
+/// Show the headings of the document, indented by level, with the pages they're on.
+///
+/// Syntax: `?outline <code block> [...]`
+///
+/// No preamble is added, so the page numbers match the document as written.
+///
+/// **Examples**
+///
+/// ```
+/// ?outline ``‍`
+/// = Introduction
+/// == Motivation
+/// = Conclusion
+/// ``‍`
+/// ```
+#[poise::command(prefix_command, track_edits, broadcast_typing)]
+async fn outline(
+	ctx: Context<'_>,
+	#[description = "Code to compile"] code: CodeBlock,
+	#[rename = "rest"]
+	#[description = "Extra message content"]
+	_: Rest,
+) -> Result<(), PoiseError> {
+	let pool = &ctx.data().pool;
 
-			for (i, image) in res.images.into_iter().enumerate() {
-				let image = CreateAttachment::bytes(image, format!("page-{}.png", i + 1));
-				message = message.attachment(image);
-			}
+	let res = pool.lock().await.outline(code.source).await;
 
-			ctx.send(message).await?;
-		}
+	let entries = match res {
+		Ok(entries) => entries,
 		Err(error) => {
 			let message = format!(
-				"An error occurred:\n```ansi\n{}\n```",
+				"{}:\n```ansi\n{}\n```",
+				guild_locale(ctx)?.error_occurred(),
 				sanitize_code_block(&format!("{error:?}")),
 			);
 			ctx.reply(message).await?;
+			return Ok(());
 		}
+	};
+
+	if entries.is_empty() {
+		ctx.reply("The document has no headings").await?;
+		return Ok(());
+	}
+
+	let mut outline = String::new();
+	for entry in &entries {
+		let indent = "  ".repeat(entry.level.saturating_sub(1));
+		writeln!(outline, "{indent}{} (page {})", entry.text, entry.page).unwrap();
 	}
 
+	let mut content = String::new();
+	let message = add_long_text(
+		CreateReply::default().reply(true),
+		&mut content,
+		"**Outline**",
+		outline.trim_end(),
+		"outline.txt",
+		false,
+	);
+	ctx.send(message.content(content)).await?;
+
 	Ok(())
 }
 
-/// Show this menu.
-#[poise::command(prefix_command, track_edits, slash_command)]
-async fn help(
-	ctx: Context<'_>,
-	#[description = "Specific command to show help about"] command: Option<String>,
-) -> Result<(), PoiseError> {
-	let config = poise::builtins::HelpConfiguration {
-		extra_text_at_bottom: "\
-Type ?help command for more info on a command.
-You can edit your message to the bot and the bot will edit its response.
-Commands prefixed with / can be used as slash commands and prefix commands.
-Commands prefixed with ? can only be used as prefix commands.
-The bot is written by mattf_. Feel free to reach out in the Typst Discord if you have any questions.
-",
-		..Default::default()
+/// The largest `typst.toml` attachment that `?manifest` will download, in bytes.
+const MAX_MANIFEST_BYTES: u32 = 64 * 1024;
+
+/// Reads the first `.toml` file attached to the command's message, if any.
+async fn manifest_attachment(ctx: Context<'_>) -> Result<Option<String>, String> {
+	let poise::Context::Prefix(prefix) = ctx else {
+		return Ok(None);
+	};
+	let Some(attachment) = prefix.msg.attachments.iter().find(|attachment| {
+		std::path::Path::new(&attachment.filename)
+			.extension()
+			.is_some_and(|extension| extension.eq_ignore_ascii_case("toml"))
+	}) else {
+		return Ok(None);
 	};
-	poise::builtins::help(ctx, command.as_deref(), config).await?;
-	Ok(())
-}
 
-/// Get a link to the bot's source.
-#[poise::command(prefix_command, slash_command)]
-async fn source(ctx: Context<'_>) -> Result<(), PoiseError> {
-	ctx.reply(format!("<{SOURCE_URL}>")).await?;
-	Ok(())
+	if attachment.size > MAX_MANIFEST_BYTES {
+		return Err(format!(
+			"The attachment is too big; the limit is {MAX_MANIFEST_BYTES} bytes"
+		));
+	}
+	let bytes = attachment
+		.download()
+		.await
+		.map_err(|error| format!("Couldn't download the attachment: {error}"))?;
+	String::from_utf8(bytes)
+		.map(Some)
+		.map_err(|_| "The attachment isn't valid UTF-8".to_owned())
 }
 
-/// Get the AST for the given code.
+/// Check a package's `typst.toml` before publishing it.
+///
+/// Syntax: `?manifest <code block>`, or attach the `typst.toml` file to `?manifest`.
 ///
-/// Syntax: `?ast <code block> [...]`
+/// The manifest is parsed the same way Typst parses it when loading a package.
 ///
 /// **Examples**
 ///
 /// ```
-/// ?ast `hello, world!`
-///
-/// ?ast ``‍`
-/// = Heading!
-///
-/// And some text.
-///
-/// #lorem(100)
+/// ?manifest ``‍`toml
+/// [package]
+/// name = "example"
+/// version = "0.1.0"
+/// entrypoint = "lib.typ"
 /// ``‍`
-///
-/// ?ast `#((3): 4)` Interesting parse result here.
 /// ```
+#[poise::command(prefix_command, track_edits)]
+async fn manifest(
+	ctx: Context<'_>,
+	#[description = "The contents of `typst.toml`"] code: Option<CodeBlock>,
+	#[rename = "rest"]
+	#[description = "Extra message content"]
+	_: Rest,
+) -> Result<(), PoiseError> {
+	let manifest = match code {
+		Some(code) => code.source,
+		None => match manifest_attachment(ctx).await {
+			Ok(Some(manifest)) => manifest,
+			Ok(None) => {
+				ctx
+					.reply("Paste the manifest in a code block or attach the `typst.toml` file")
+					.await?;
+				return Ok(());
+			}
+			Err(message) => {
+				ctx.reply(message).await?;
+				return Ok(());
+			}
+		},
+	};
+
+	let pool = &ctx.data().pool;
+
+	let res = pool.lock().await.manifest(manifest).await;
+
+	let message = match res {
+		Ok(Ok(summary)) => {
+			let mut message = format!(
+				"The manifest is valid.\n- Package: `{}:{}`\n- Entrypoint: `{}`\n",
+				summary.name, summary.version, summary.entrypoint,
+			);
+			if let Some(compiler) = &summary.compiler {
+				writeln!(message, "- Requires Typst {compiler} or later").unwrap();
+				if !summary.compiler_supported {
+					writeln!(
+						message,
+						"  - Note: this is newer than the bot's Typst; see `?version`"
+					)
+					.unwrap();
+				}
+			}
+			if let Some(template) = &summary.template {
+				writeln!(message, "- Template entrypoint: `{template}`").unwrap();
+			}
+			message
+		}
+		Ok(Err(error)) => format!(
+			"The manifest is invalid:\n```\n{}\n```",
+			sanitize_code_block(&error),
+		),
+		Err(error) => format!(
+			"{}:\n```ansi\n{}\n```",
+			guild_locale(ctx)?.error_occurred(),
+			sanitize_code_block(&format!("{error:?}")),
+		),
+	};
+	ctx.reply(message).await?;
+
+	Ok(())
+}
+
+fn format_pdf_info(info: &PdfInfo) -> String {
+	// Precision loss is irrelevant for display.
+	#![allow(clippy::cast_precision_loss)]
+
+	const MAX_PAGES_LISTED: usize = 10;
+
+	let mut message = String::new();
+
+	let kib = info.bytes as f64 / 1024.0;
+	writeln!(message, "**PDF size:** {kib:.1} KiB").unwrap();
+
+	if let Some(title) = &info.title {
+		writeln!(message, "**Title:** {title}").unwrap();
+	}
+	if !info.authors.is_empty() {
+		writeln!(message, "**Authors:** {}", info.authors.join(", ")).unwrap();
+	}
+
+	let num_pages = info.page_sizes.len();
+	writeln!(message, "**Pages:** {num_pages}").unwrap();
+	for (i, (width, height)) in info.page_sizes.iter().enumerate().take(MAX_PAGES_LISTED) {
+		writeln!(message, "- Page {}: {width:.1} × {height:.1} pt", i + 1).unwrap();
+	}
+	if num_pages > MAX_PAGES_LISTED {
+		writeln!(message, "- and {} more", num_pages - MAX_PAGES_LISTED).unwrap();
+	}
+
+	if info.fonts.is_empty() {
+		writeln!(message, "**Fonts:** none").unwrap();
+	} else {
+		let fonts = info
+			.fonts
+			.iter()
+			.map(|font| format!("`{font}`"))
+			.collect::<Vec<_>>();
+		writeln!(message, "**Fonts:** {}", fonts.join(", ")).unwrap();
+	}
+
+	message
+}
+
+/// Compile the given code to a PDF and show information about it.
+///
+/// Syntax: `?pdfinfo <code block> [...]`
+///
+/// No preamble is added, so the document is compiled exactly as written.
 #[poise::command(prefix_command, track_edits, broadcast_typing)]
-async fn ast(
+async fn pdfinfo(
 	ctx: Context<'_>,
-	#[description = "Code to parse"] code: CodeBlock,
+	#[description = "Code to compile"] code: CodeBlock,
 	#[rename = "rest"]
 	#[description = "Extra message content"]
 	_: Rest,
 ) -> Result<(), PoiseError> {
 	let pool = &ctx.data().pool;
 
-	let res = pool.lock().await.ast(code.source).await;
+	let res = pool.lock().await.pdf_info(code.source).await;
 
 	match res {
-		Ok(ast) => {
-			let message = format!("```ansi\n{}```", sanitize_code_block(&ast));
-			ctx.reply(message).await?;
+		Ok(info) => {
+			ctx.reply(format_pdf_info(&info)).await?;
 		}
 		Err(error) => {
 			let message = format!(
-				"An error occurred:\n```ansi\n{}```",
+				"{}:\n```ansi\n{}\n```",
+				guild_locale(ctx)?.error_occurred(),
 				sanitize_code_block(&format!("{error:?}")),
 			);
 			ctx.reply(message).await?;
@@ -472,7 +3140,204 @@ The bot is using Typst [version **{typst_version}**](<https://github.com/typst/t
 			ctx.reply(message).await?;
 		}
 		Err(error) => {
-			let message = format!("An error occurred:\n```ansi\n{error}```");
+			let message = format!(
+				"{}:\n```ansi\n{error}```",
+				guild_locale(ctx)?.error_occurred()
+			);
+			ctx.reply(message).await?;
+		}
+	}
+
+	Ok(())
+}
+
+/// Escape the given text into a Typst string literal.
+///
+/// Syntax: `?escape <text>`
+///
+/// **Examples**
+///
+/// ```
+/// ?escape say "hi" to C:\Users
+/// ```
+#[poise::command(prefix_command, slash_command, track_edits)]
+async fn escape(
+	ctx: Context<'_>,
+	#[rest]
+	#[description = "The text to escape"]
+	text: String,
+) -> Result<(), PoiseError> {
+	let literal = string_literal(&text);
+	let message = format!("```typ\n{}\n```", sanitize_code_block(&literal));
+	ctx.reply(message).await?;
+
+	Ok(())
+}
+
+/// Show the preamble that `?render` adds to the code for the given flags.
+///
+/// Syntax: `?preamble [flags]`
+///
+/// The flags are the same as for `?render`; see `?help render`. The server's own preamble, if it has one, is included.
+///
+/// **Examples**
+///
+/// ```
+/// ?preamble theme=light pagesize=a4
+/// ```
+#[poise::command(prefix_command, track_edits)]
+async fn preamble(
+	ctx: Context<'_>,
+	#[description = "Flags"] flags: RenderFlags,
+) -> Result<(), PoiseError> {
+	let use_preamble = !preamble_disabled(ctx)?;
+	let guild_preamble = guild_preamble(ctx)?;
+
+	let mut content = String::new();
+	if !use_preamble {
+		writeln!(
+			content,
+			"The automatic preamble is disabled in this server, so `pagesize` and `theme` have no effect."
+		)
+		.unwrap();
+	}
+	for (variant, preamble) in variants(&flags) {
+		let source = with_preambles(
+			String::new(),
+			use_preamble.then_some(preamble),
+			guild_preamble.as_deref(),
+		);
+		if let Some(variant) = variant {
+			writeln!(content, "{variant}:").unwrap();
+		}
+		if source.trim().is_empty() {
+			writeln!(content, "No preamble is added.").unwrap();
+		} else {
+			writeln!(content, "```typ\n{}```", sanitize_code_block(&source)).unwrap();
+		}
+	}
+	ctx.reply(content).await?;
+
+	Ok(())
+}
+
+/// Show the limits that renders are subject to.
+#[poise::command(prefix_command, slash_command)]
+async fn limits(ctx: Context<'_>) -> Result<(), PoiseError> {
+	let pool = &ctx.data().pool;
+
+	let res = pool.lock().await.limits().await;
+
+	match res {
+		Ok(LimitsResponse {
+			pages,
+			bytes,
+			page_size,
+			source_length,
+		}) => {
+			let progress_timeout = PROGRESS_TIMEOUT.as_secs();
+			let base_timeout = BASE_TOTAL_TIMEOUT.as_secs();
+			let timeout_per_kib = TOTAL_TIMEOUT_PER_KIB.as_secs();
+			let max_timeout = MAX_TOTAL_TIMEOUT.as_secs();
+			let megabytes = bytes / (1024 * 1024);
+			let source_length = source_length.map_or_else(String::new, |source_length| {
+				format!("- Code can be at most **{source_length}** bytes long, including the preamble added when rendering.\n")
+			});
+			let message = format!(
+				"\
+{source_length}\
+- At most **{pages}** pages are rendered; the rest are skipped.
+- The rendered images can be at most **{megabytes} MiB** in total.
+- Pages can be at most **{page_size} pt** wide or tall.
+- Rendering times out after **{base_timeout} seconds**, plus **{timeout_per_kib} seconds** per KiB of code up to **{max_timeout} seconds**, or after **{progress_timeout} seconds** without progress.
+- Packages are downloaded from the Typst package registry when first used; see `?packages` for those already downloaded.\
+"
+			);
+			ctx.reply(message).await?;
+		}
+		Err(error) => {
+			let message = format!(
+				"{}:\n```ansi\n{error}```",
+				guild_locale(ctx)?.error_occurred()
+			);
+			ctx.reply(message).await?;
+		}
+	}
+
+	Ok(())
+}
+
+/// List the packages that are already downloaded, so they can be used without a delay.
+///
+/// Syntax: `?packages [page]`
+#[poise::command(prefix_command, slash_command, track_edits)]
+async fn packages(
+	ctx: Context<'_>,
+	#[description = "The page of the list to show"]
+	#[min = 1]
+	page: Option<usize>,
+) -> Result<(), PoiseError> {
+	const PACKAGES_PER_PAGE: usize = 20;
+
+	let pool = &ctx.data().pool;
+
+	let res = pool.lock().await.list_packages().await;
+
+	let packages = match res {
+		Ok(packages) => packages,
+		Err(error) => {
+			let message = format!(
+				"{}:\n```ansi\n{error}```",
+				guild_locale(ctx)?.error_occurred()
+			);
+			ctx.reply(message).await?;
+			return Ok(());
+		}
+	};
+
+	if packages.is_empty() {
+		ctx.reply("No packages are cached").await?;
+		return Ok(());
+	}
+
+	let num_pages = packages.len().div_ceil(PACKAGES_PER_PAGE);
+	let page = page.unwrap_or(1).clamp(1, num_pages);
+	let mut message = packages
+		.iter()
+		.skip((page - 1) * PACKAGES_PER_PAGE)
+		.take(PACKAGES_PER_PAGE)
+		.fold(String::new(), |mut acc, package| {
+			writeln!(acc, "- `{package}`").unwrap();
+			acc
+		});
+	write!(message, "Page {page} of {num_pages}").unwrap();
+	if page < num_pages {
+		write!(message, "; use `?packages {}` for more", page + 1).unwrap();
+	}
+
+	ctx.reply(message).await?;
+
+	Ok(())
+}
+
+/// Reload the worker's fonts from disk (owner only).
+#[poise::command(prefix_command, rename = "reload-fonts", owners_only, hide_in_help)]
+async fn reload_fonts(ctx: Context<'_>) -> Result<(), PoiseError> {
+	let pool = &ctx.data().pool;
+
+	let res = pool.lock().await.reload_fonts().await;
+
+	match res {
+		Ok(ReloadedFonts { fonts }) => {
+			ctx
+				.reply(format!("Reloaded fonts; {fonts} are now available"))
+				.await?;
+		}
+		Err(error) => {
+			let message = format!(
+				"{}:\n```ansi\n{error}```",
+				guild_locale(ctx)?.error_occurred()
+			);
 			ctx.reply(message).await?;
 		}
 	}
@@ -480,6 +3345,218 @@ The bot is using Typst [version **{typst_version}**](<https://github.com/typst/t
 	Ok(())
 }
 
+/// Formats an optional setting, showing "unset" for `None`.
+fn format_setting(setting: Option<impl Display>) -> String {
+	setting.map_or_else(|| "unset".into(), |setting| format!("`{setting}`"))
+}
+
+fn format_worker_config(config: &ConfigResponse) -> String {
+	let excluded_fonts = if config.excluded_fonts.is_empty() {
+		None
+	} else {
+		Some(config.excluded_fonts.join(", "))
+	};
+	let jpeg_fallback = if config.jpeg_fallback.is_empty() {
+		None
+	} else {
+		Some(
+			config
+				.jpeg_fallback
+				.iter()
+				.map(ToString::to_string)
+				.collect::<Vec<_>>()
+				.join(", "),
+		)
+	};
+
+	format!(
+		"\
+- Cache directory: `{}`, with {} packages
+- Fonts: {} loaded; font directory {}, excluded families {}
+- Watermark: {}
+- JPEG fallback qualities: {}
+- Maximum source length: {}
+- Package downloads: {} s to connect, {} s per read, user agent `{}`",
+		config.cache_directory,
+		config.cached_packages,
+		config.fonts,
+		format_setting(config.font_directory.as_ref()),
+		format_setting(excluded_fonts),
+		if config.watermark { "yes" } else { "no" },
+		format_setting(jpeg_fallback),
+		format_setting(
+			config
+				.max_source_length
+				.map(|length| format!("{length} bytes"))
+		),
+		config.package_connect_timeout,
+		config.package_read_timeout,
+		config.package_user_agent,
+	)
+}
+
+/// The longest response that `?raw-request` attaches, past which it's cut off.
+/// Images are written as arrays of numbers, so a render's response can be far bigger than its images.
+const MAX_RAW_RESPONSE_LENGTH: usize = 8 * 1024 * 1024;
+
+/// Send a JSON-encoded request to the worker and show its response (owner only).
+///
+/// Syntax: `?raw-request <code block>`
+///
+/// **Examples**
+///
+/// ```
+/// ?raw-request `"Version"`
+///
+/// ?raw-request ``‍`json
+/// {"Ast": {"code": "= Hi", "spans": false}}
+/// ``‍`
+/// ```
+#[poise::command(prefix_command, rename = "raw-request", owners_only, hide_in_help)]
+async fn raw_request(
+	ctx: Context<'_>,
+	#[description = "The request, as JSON"] request: CodeBlock,
+) -> Result<(), PoiseError> {
+	let request: protocol::Request = match serde_json::from_str(&request.source) {
+		Ok(request) => request,
+		Err(error) => {
+			ctx.reply(format!("Invalid request: {error}")).await?;
+			return Ok(());
+		}
+	};
+
+	let res = ctx.data().pool.lock().await.raw(request).await;
+	let mut content = String::new();
+	let mut message = CreateReply::default().reply(true);
+	match res {
+		Ok(response) => {
+			let mut json = serde_json::to_string_pretty(&response)?;
+			if json.len() > MAX_RAW_RESPONSE_LENGTH {
+				let mut end = MAX_RAW_RESPONSE_LENGTH;
+				while !json.is_char_boundary(end) {
+					end -= 1;
+				}
+				json.truncate(end);
+				json.push_str("\n(cut off)");
+			}
+			message = add_long_text(
+				message,
+				&mut content,
+				"Response",
+				&json,
+				"response.json",
+				false,
+			);
+		}
+		Err(error) => write!(
+			content,
+			"{}:\n```ansi\n{error:?}```",
+			guild_locale(ctx)?.error_occurred()
+		)
+		.unwrap(),
+	}
+	ctx.send(message.content(content)).await?;
+
+	Ok(())
+}
+
+/// Show the effective configuration of the bot and the worker (owner only).
+///
+/// Secrets such as the Discord token are never shown.
+#[poise::command(prefix_command, owners_only, hide_in_help)]
+async fn config(ctx: Context<'_>) -> Result<(), PoiseError> {
+	let idle_timeout = idle_timeout().map(|timeout| format!("{} s", timeout.as_secs()));
+	let watchdog_interval = watchdog_interval().map(|interval| format!("{} s", interval.as_secs()));
+	let aliases = std::env::var("COMMAND_ALIASES").ok();
+	let database = std::env::var_os("DB_PATH").map(|path| path.to_string_lossy().into_owned());
+
+	let mut message = format!(
+		"\
+**Bot**
+- Maximum attachments: {}
+- Worker idle timeout: {}
+- Worker watchdog interval: {}
+- Worker retries after a crash: {}
+- Render timeouts: {} s, plus {} s per KiB up to {} s, or {} s without progress
+- Error notice: {}
+- Command aliases: {}
+- Database: {}
+",
+		ctx.data().max_attachments,
+		format_setting(idle_timeout),
+		format_setting(watchdog_interval),
+		worker_retries(),
+		BASE_TOTAL_TIMEOUT.as_secs(),
+		TOTAL_TIMEOUT_PER_KIB.as_secs(),
+		MAX_TOTAL_TIMEOUT.as_secs(),
+		PROGRESS_TIMEOUT.as_secs(),
+		format_setting(ctx.data().error_notice.as_ref()),
+		format_setting(aliases),
+		format_setting(database),
+	);
+
+	let res = ctx.data().pool.lock().await.config().await;
+	message.push_str("**Worker**\n");
+	match res {
+		Ok(config) => message.push_str(&format_worker_config(&config)),
+		Err(error) => write!(
+			message,
+			"{}:\n```ansi\n{error}```",
+			guild_locale(ctx)?.error_occurred()
+		)
+		.unwrap(),
+	}
+
+	ctx.reply(message).await?;
+
+	Ok(())
+}
+
+/// Toggle whether a user is blocked from using the bot (owner only).
+///
+/// Syntax: `?blacklist <user>`
+///
+/// Commands from blacklisted users are ignored in every server.
+#[poise::command(prefix_command, owners_only, hide_in_help)]
+async fn blacklist(
+	ctx: Context<'_>,
+	#[description = "The user to block or unblock"] user: User,
+) -> Result<(), PoiseError> {
+	let message = {
+		let database = &ctx.data().database;
+		let database = database.lock().map_err(|_| "db mutex poisoned, oops")?;
+		let num_rows = database.execute(
+			"delete from blacklist where user = :user",
+			named_params!(":user": user.id.get()),
+		)?;
+		if num_rows > 0 {
+			format!("{} can use the bot again", user.name)
+		} else {
+			database.execute(
+				"insert into blacklist (user) values (:user)",
+				named_params!(":user": user.id.get()),
+			)?;
+			format!("{} is now blacklisted", user.name)
+		}
+	};
+
+	ctx.reply(message).await?;
+
+	Ok(())
+}
+
+/// Rejects commands from blacklisted users, without replying so as not to give them attention.
+fn not_blacklisted(ctx: Context<'_>) -> Result<bool, PoiseError> {
+	let blacklisted = ctx
+		.data()
+		.database
+		.lock()
+		.map_err(|_| "db mutex poisoned, oops")?
+		.prepare("select 1 from blacklist where user = :user")?
+		.exists(named_params!(":user": ctx.author().id.get()))?;
+	Ok(!blacklisted)
+}
+
 #[derive(serde::Serialize)]
 struct TagName(String);
 
@@ -563,6 +3640,21 @@ fn interpolate<'a>(template: &str, mut params: impl Iterator<Item = &'a str>) ->
 	buf
 }
 
+/// Looks up the stored text of the tag in the guild, failing outside of guilds.
+fn tag_text(ctx: Context<'_>, tag_name: &str) -> Result<Option<String>, PoiseError> {
+	let database = &ctx.data().database;
+	let guild_id = ctx.guild_id().ok_or("no guild id, so no tags")?.get();
+	let text = database
+		.lock()
+		.map_err(|_| "db mutex poisoned, oops")?
+		.prepare("select text from tags where name = :name and guild = :guild")?
+		.query(named_params!(":name": tag_name, ":guild": guild_id))?
+		.next()?
+		.map(|row| row.get::<_, String>("text"))
+		.transpose()?;
+	Ok(text)
+}
+
 /// Print the content of a tag by name.
 ///
 /// Syntax: `?tag <tag name> <parameters...>`
@@ -580,33 +3672,118 @@ async fn tag(
 	TagName(tag_name): TagName,
 	#[description = "Any parameters for the tag"] parameters: Vec<String>,
 ) -> Result<(), PoiseError> {
-	let database = &ctx.data().database;
-	let guild_id = ctx.guild_id().ok_or("no guild id, so no tags")?.get();
-	let text = database
-		.lock()
-		.map_err(|_| "db mutex poisoned, oops")?
-		.prepare("select text from tags where name = :name and guild = :guild")?
-		.query(named_params!(":name": tag_name, ":guild": guild_id))?
-		.next()?
-		.map(|row| row.get::<_, String>("text"))
-		.transpose()?;
-	let text = text.unwrap_or_else(|| "That tag is not defined.".into());
+	let text = match tag_text(ctx, &tag_name)? {
+		Some(text) => text,
+		None => guild_locale(ctx)?.tag_not_defined().into(),
+	};
 	let text = interpolate(&text, parameters.iter().map(String::as_str));
 	ctx.say(text).await?;
 	Ok(())
 }
 
+/// Print the stored text of a tag, without filling in its placeholders.
+///
+/// Syntax: `?tag-raw <tag name>`
+///
+/// This shows exactly what was set with `?set-tag`, including any `%s` placeholders,
+/// so that it can be copied and edited.
+#[poise::command(prefix_command, slash_command, rename = "tag-raw", track_edits)]
+async fn tag_raw(
+	ctx: Context<'_>,
+	#[rename = "tag_name"]
+	#[description = "The tag to show"]
+	#[autocomplete = "tag_autocomplete"]
+	TagName(tag_name): TagName,
+) -> Result<(), PoiseError> {
+	let message = match tag_text(ctx, &tag_name)? {
+		Some(text) => format!("```\n{}\n```", sanitize_code_block(&text)),
+		None => guild_locale(ctx)?.tag_not_defined().into(),
+	};
+	ctx.say(message).await?;
+	Ok(())
+}
+
+/// Whether the author can set and delete tags: they need the server's tag editor role, if it has
+/// one, or the Kick Members permission.
+///
+/// Replies to explain when they can't.
+async fn can_edit_tags(ctx: Context<'_>) -> Result<bool, PoiseError> {
+	let Some(guild_id) = ctx.guild_id() else {
+		// There are no tags outside of servers, which the command itself reports.
+		return Ok(true);
+	};
+
+	let role = ctx
+		.data()
+		.database
+		.lock()
+		.map_err(|_| "db mutex poisoned, oops")?
+		.prepare("select role from tag_editor_roles where guild = :guild")?
+		.query(named_params!(":guild": guild_id.get()))?
+		.next()?
+		.map(|row| row.get::<_, u64>("role"))
+		.transpose()?
+		.map(RoleId::new);
+
+	let member = ctx
+		.author_member()
+		.await
+		.ok_or("couldn't get the member who sent the command")?;
+	if role.is_some_and(|role| member.roles.contains(&role)) {
+		return Ok(true);
+	}
+
+	let guild = guild_id.to_partial_guild(ctx).await?;
+	let channel = ctx
+		.channel_id()
+		.to_channel(ctx)
+		.await?
+		.guild()
+		.ok_or("the command wasn't sent in a server channel")?;
+	if guild.user_permissions_in(&channel, &member).kick_members() {
+		return Ok(true);
+	}
+
+	let requirement = if role.is_some() {
+		"the server's tag editor role or the Kick Members permission"
+	} else {
+		"the Kick Members permission"
+	};
+	let message = CreateReply::default()
+		.content(format!(
+			"You need {requirement} to use `{}`",
+			ctx.command().name,
+		))
+		.reply(true)
+		.ephemeral(true);
+	ctx.send(message).await?;
+	Ok(false)
+}
+
+/// The pop-up for entering the text of a tag with `/set-tag`,
+/// which is easier than a slash command option for long or multi-line text, especially on mobile.
+#[derive(poise::Modal)]
+#[name = "Set Tag"]
+struct TagModal {
+	#[name = "Tag text"]
+	#[paragraph]
+	#[max_length = 1000]
+	text: String,
+}
+
 /// Set the content of a tag (privileged).
 ///
 /// Syntax: `?set-tag <tag name> <tag text>`
 ///
+/// With `/set-tag`, leave out the text to enter it in a pop-up instead, filled in with the tag's current text.
+///
 /// Note that tags are local to the guild.
 #[poise::command(
 	prefix_command,
 	slash_command,
 	rename = "set-tag",
 	invoke_on_edit,
-	required_permissions = "KICK_MEMBERS"
+	check = "can_edit_tags"
 )]
 async fn set_tag(
 	ctx: Context<'_>,
@@ -615,13 +3792,40 @@ async fn set_tag(
 	TagName(tag_name): TagName,
 	#[rest]
 	#[rename = "tag_text"]
-	#[description = "The text of the tag"]
+	#[description = "The text of the tag; leave this out to enter it in a pop-up"]
 	#[max_length = 1000]
-	tag_text: String,
+	tag_text: Option<String>,
 ) -> Result<(), PoiseError> {
 	let database = &ctx.data().database;
 
 	let guild_id = ctx.guild_id().ok_or("no guild id, so no tags")?.get();
+
+	let tag_text = match (tag_text, ctx) {
+		(Some(tag_text), _) => tag_text,
+		(None, poise::Context::Application(app_ctx)) => {
+			let current = database
+				.lock()
+				.map_err(|_| "db mutex poisoned, oops")?
+				.prepare("select text from tags where name = :name and guild = :guild")?
+				.query(named_params!(":name": tag_name, ":guild": guild_id))?
+				.next()?
+				.map(|row| row.get::<_, String>("text"))
+				.transpose()?;
+			let defaults = current.map(|text| TagModal { text });
+			match poise::execute_modal(app_ctx, defaults, None).await? {
+				Some(TagModal { text }) => text,
+				// The pop-up was closed or timed out.
+				None => return Ok(()),
+			}
+		}
+		(None, poise::Context::Prefix(_)) => {
+			ctx
+				.reply("Missing the tag text; write it after the tag name")
+				.await?;
+			return Ok(());
+		}
+	};
+
 	database.lock()
 		.map_err(|_| "db mutex poisoned, oops")?
 		.execute(
@@ -649,7 +3853,7 @@ async fn set_tag(
 	rename = "delete-tag",
  // It doesn't undo deletion, so it's not exactly a purely edit-tracked system, but users still expect this type of behavior.
 	invoke_on_edit,
-	required_permissions = "KICK_MEMBERS"
+	check = "can_edit_tags"
 )]
 async fn delete_tag(
 	ctx: Context<'_>,
@@ -732,40 +3936,485 @@ async fn list_tags(
 	Ok(())
 }
 
+/// Set the role that can set and delete tags in this server (privileged).
+///
+/// Syntax: `?set-tag-role [role]`
+///
+/// Members with the Kick Members permission can always edit tags.
+/// Omit the role to go back to only allowing them.
+#[poise::command(
+	prefix_command,
+	slash_command,
+	rename = "set-tag-role",
+	required_permissions = "MANAGE_GUILD"
+)]
+async fn set_tag_role(
+	ctx: Context<'_>,
+	#[description = "The role that can edit tags"] role: Option<Role>,
+) -> Result<(), PoiseError> {
+	let guild_id = ctx.guild_id().ok_or("no guild id, so no settings")?.get();
+
+	let message = {
+		let database = &ctx.data().database;
+		let database = database.lock().map_err(|_| "db mutex poisoned, oops")?;
+		if let Some(role) = &role {
+			database.execute(
+				"insert into tag_editor_roles (guild, role) values (:guild, :role) on conflict do update set role = :role",
+				named_params!(":guild": guild_id, ":role": role.id.get()),
+			)?;
+			format!("Members with the **{}** role can now edit tags", role.name)
+		} else {
+			database.execute(
+				"delete from tag_editor_roles where guild = :guild",
+				named_params!(":guild": guild_id),
+			)?;
+			"Only members with the Kick Members permission can now edit tags".to_owned()
+		}
+	};
+
+	ctx.reply(message).await?;
+
+	Ok(())
+}
+
+/// Toggle whether `?render` adds a preamble in this server (privileged).
+///
+/// Syntax: `?toggle-preamble`
+///
+/// With the preamble disabled, code is rendered exactly as written.
+#[poise::command(
+	prefix_command,
+	slash_command,
+	rename = "toggle-preamble",
+	required_permissions = "MANAGE_GUILD"
+)]
+async fn toggle_preamble(ctx: Context<'_>) -> Result<(), PoiseError> {
+	let guild_id = ctx.guild_id().ok_or("no guild id, so no settings")?.get();
+
+	let message = {
+		let database = &ctx.data().database;
+		let database = database.lock().map_err(|_| "db mutex poisoned, oops")?;
+		let num_rows = database.execute(
+			"delete from preamble_disabled where guild = :guild",
+			named_params!(":guild": guild_id),
+		)?;
+		if num_rows > 0 {
+			"The preamble is enabled again in this server"
+		} else {
+			database.execute(
+				"insert into preamble_disabled (guild) values (:guild)",
+				named_params!(":guild": guild_id),
+			)?;
+			"The preamble is now disabled in this server; code will be rendered exactly as written"
+		}
+	};
+
+	ctx.reply(message).await?;
+
+	Ok(())
+}
+
+/// Set the language of the bot's messages in this server (privileged).
+///
+/// Syntax: `?set-language [language]`
+///
+/// The language is given by its two-letter code, like `de`. Omit it to list the languages.
+/// Only the messages about rendering are translated so far.
+#[poise::command(
+	prefix_command,
+	slash_command,
+	rename = "set-language",
+	required_permissions = "MANAGE_GUILD"
+)]
+async fn set_language(
+	ctx: Context<'_>,
+	#[description = "The two-letter code of the language"] language: Option<String>,
+) -> Result<(), PoiseError> {
+	let guild_id = ctx.guild_id().ok_or("no guild id, so no settings")?.get();
+
+	let languages = Locale::ALL
+		.iter()
+		.map(|locale| format!("`{}` ({})", locale.code(), locale.name()))
+		.collect::<Vec<_>>()
+		.join(", ");
+	let Some(language) = language else {
+		ctx.reply(format!("The languages are {languages}")).await?;
+		return Ok(());
+	};
+	let Some(locale) = Locale::from_code(&language) else {
+		ctx
+			.reply(format!(
+				"Unknown language {language:?}; the languages are {languages}"
+			))
+			.await?;
+		return Ok(());
+	};
+
+	{
+		let database = &ctx.data().database;
+		let database = database.lock().map_err(|_| "db mutex poisoned, oops")?;
+		if locale == Locale::default() {
+			database.execute(
+				"delete from guild_locales where guild = :guild",
+				named_params!(":guild": guild_id),
+			)?;
+		} else {
+			database.execute(
+				"insert into guild_locales (guild, locale) values (:guild, :locale) on conflict do update set locale = :locale",
+				named_params!(":guild": guild_id, ":locale": locale.code()),
+			)?;
+		}
+	}
+
+	ctx
+		.reply(format!(
+			"The language of this server is now {}",
+			locale.name()
+		))
+		.await?;
+
+	Ok(())
+}
+
+/// Set a preamble that is added to every render in this server (privileged).
+///
+/// Syntax: `?set-guild-preamble [preamble]`
+///
+/// The server's preamble comes after the automatic one, so it can override the page size and theme.
+/// Omit the preamble to remove it.
+///
+/// **Examples**
+///
+/// ```
+/// ?set-guild-preamble #set text(font: "New Computer Modern", size: 12pt)
+/// ```
+#[poise::command(
+	prefix_command,
+	slash_command,
+	rename = "set-guild-preamble",
+	required_permissions = "MANAGE_GUILD"
+)]
+async fn set_guild_preamble(
+	ctx: Context<'_>,
+	#[rest]
+	#[description = "Typst code to add before every render"]
+	#[max_length = 1000]
+	preamble: Option<String>,
+) -> Result<(), PoiseError> {
+	const MAX_LENGTH: usize = 1000;
+
+	let guild_id = ctx.guild_id().ok_or("no guild id, so no settings")?.get();
+	let preamble = preamble.filter(|preamble| !preamble.trim().is_empty());
+
+	let message = {
+		let database = &ctx.data().database;
+		let database = database.lock().map_err(|_| "db mutex poisoned, oops")?;
+		if let Some(preamble) = &preamble {
+			if preamble.len() > MAX_LENGTH {
+				return Err(format!("the preamble can be at most {MAX_LENGTH} bytes").into());
+			}
+			database.execute(
+				"insert into guild_preambles (guild, preamble) values (:guild, :preamble) on conflict do update set preamble = :preamble",
+				named_params!(":guild": guild_id, ":preamble": preamble),
+			)?;
+			"The server preamble is updated"
+		} else {
+			database.execute(
+				"delete from guild_preambles where guild = :guild",
+				named_params!(":guild": guild_id),
+			)?;
+			"The server preamble is removed"
+		}
+	};
+
+	ctx.reply(message).await?;
+
+	Ok(())
+}
+
+/// Show the users who have rendered the most in this server.
+#[poise::command(prefix_command, slash_command, track_edits)]
+async fn leaderboard(ctx: Context<'_>) -> Result<(), PoiseError> {
+	let reply = {
+		let database = &ctx.data().database;
+		let database = database.lock().map_err(|_| "db mutex poisoned, oops")?;
+		let mut statement = database.prepare(
+			"select user, count from render_counts where guild = :guild order by count desc limit 10",
+		)?;
+		let guild_id = ctx
+			.guild_id()
+			.ok_or("no guild id, so no leaderboard")?
+			.get();
+		let mut results = statement.query_map(named_params!(":guild": guild_id), |row| {
+			Ok((row.get::<_, u64>("user")?, row.get::<_, u64>("count")?))
+		})?;
+		let mut place = 0;
+		results.try_fold(String::new(), |mut acc, row| {
+			let (user, count) = row?;
+			place += 1;
+			let s = if count == 1 { "" } else { "s" };
+			writeln!(acc, "{place}. <@{user}>: {count} render{s}").unwrap();
+			Ok::<_, rusqlite::Error>(acc)
+		})?
+	};
+
+	let reply = if reply.is_empty() {
+		"Nobody has rendered anything yet"
+	} else {
+		&reply
+	};
+
+	ctx.reply(reply).await?;
+
+	Ok(())
+}
+
 async fn handle_error(
 	error: poise::FrameworkError<'_, Data, Box<dyn std::error::Error + Send + Sync>>,
 ) -> serenity::Result<()> {
-	if let poise::FrameworkError::ArgumentParse {
-		ctx, input, error, ..
-	} = error
-	{
-		let name = &ctx.command().name;
-		let usage = format!(
-			"Use `?help {name}` for usage. Feel free to edit or delete your message and the bot will react.",
-		);
-		let response = input.map_or_else(
-			|| format!("**{error}**\n{usage}"),
-			|input| format!("**Cannot parse `{input}` as argument: {error}**\n{usage}"),
+	match error {
+		poise::FrameworkError::ArgumentParse {
+			ctx, input, error, ..
+		} => {
+			let name = &ctx.command().name;
+			let usage = format!(
+				"Use `?help {name}` for usage. Feel free to edit or delete your message and the bot will react.",
+			);
+			let response = input.map_or_else(
+				|| format!("**{error}**\n{usage}"),
+				|input| format!("**Cannot parse `{input}` as argument: {error}**\n{usage}"),
+			);
+			ctx.reply(response).await?;
+			Ok(())
+		}
+		poise::FrameworkError::CooldownHit {
+			remaining_cooldown,
+			ctx,
+			..
+		} => {
+			// Round up so we never say to wait 0 seconds.
+			let seconds = remaining_cooldown.as_millis().div_ceil(1000);
+			let response = format!(
+				"You're on cooldown for `{name}`; try again in {seconds}s.",
+				name = ctx.command().name,
+			);
+			ctx
+				.send(
+					CreateReply::default()
+						.content(response)
+						.reply(true)
+						.ephemeral(true),
+				)
+				.await?;
+			Ok(())
+		}
+		// Checks that fail without an error have already replied if they wanted to.
+		poise::FrameworkError::CommandCheckFailed { error: None, .. } => Ok(()),
+		error => poise::builtins::on_error(error).await,
+	}
+}
+
+/// Periodically stops the worker if it has been idle for `idle_timeout`.
+async fn stop_idle_worker(pool: Arc<Mutex<Worker>>, idle_timeout: Duration) {
+	let mut interval = tokio::time::interval(idle_timeout / 4);
+	loop {
+		interval.tick().await;
+		if let Err(error) = pool.lock().await.stop_if_idle(idle_timeout).await {
+			tracing::error!(?error, "Error while stopping idle worker");
+		}
+	}
+}
+
+/// Periodically checks that the worker still responds, restarting it if not,
+/// so that a worker that has wedged while idle is replaced before the next render.
+///
+/// The check is skipped while the worker is in use, since requests have their own timeouts.
+async fn watch_worker(pool: Arc<Mutex<Worker>>, interval: Duration) {
+	let mut interval = tokio::time::interval(interval);
+	loop {
+		interval.tick().await;
+		let Ok(mut worker) = pool.try_lock() else {
+			continue;
+		};
+		if let Err(error) = worker.check_health().await {
+			tracing::error!(?error, "Error while restarting unresponsive worker");
+		}
+	}
+}
+
+/// Starts the background tasks that look after the worker, as configured.
+fn spawn_worker_tasks(pool: &Arc<Mutex<Worker>>) {
+	if let Some(idle_timeout) = idle_timeout() {
+		tokio::spawn(stop_idle_worker(Arc::clone(pool), idle_timeout));
+	}
+	if let Some(interval) = watchdog_interval() {
+		tokio::spawn(watch_worker(Arc::clone(pool), interval));
+	}
+}
+
+/// Adds the aliases from the `COMMAND_ALIASES` env var,
+/// which looks like `render:rn,typst ast:tree`.
+///
+/// Panics if the config is malformed or names a command that doesn't exist.
+fn add_aliases(commands: &mut [poise::Command<Data, PoiseError>], config: &str) {
+	for entry in config.split_whitespace() {
+		let (name, aliases) = entry.split_once(':').unwrap_or_else(|| {
+			panic!("`COMMAND_ALIASES` entry {entry:?} should look like `command:alias1,alias2`")
+		});
+		let command = commands
+			.iter_mut()
+			.find(|command| command.name == name)
+			.unwrap_or_else(|| panic!("`COMMAND_ALIASES` names unknown command {name:?}"));
+		command.aliases.extend(
+			aliases
+				.split(',')
+				.filter(|alias| !alias.is_empty())
+				.map(String::from),
 		);
-		ctx.reply(response).await?;
-		Ok(())
-	} else {
-		poise::builtins::on_error(error).await
 	}
 }
 
-pub async fn run() {
+fn open_database() -> Connection {
 	let database = Connection::open_with_flags(
 		std::env::var_os("DB_PATH").expect("need `DB_PATH` env var"),
 		OpenFlags::SQLITE_OPEN_READ_WRITE | OpenFlags::SQLITE_OPEN_CREATE,
 	)
 	.unwrap();
 	database.execute("create table if not exists tags (name text not null, guild integer not null, text text not null, unique (name, guild)) strict", []).unwrap();
-	let database = std::sync::Mutex::new(database);
+	database
+		.execute(
+			"create table if not exists preamble_disabled (guild integer not null primary key) strict",
+			[],
+		)
+		.unwrap();
+	database.execute("create table if not exists guild_preambles (guild integer not null primary key, preamble text not null) strict", []).unwrap();
+	database.execute("create table if not exists guild_locales (guild integer not null primary key, locale text not null) strict", []).unwrap();
+	database.execute("create table if not exists tag_editor_roles (guild integer not null primary key, role integer not null) strict", []).unwrap();
+	database
+		.execute(
+			"create table if not exists blacklist (user integer not null primary key) strict",
+			[],
+		)
+		.unwrap();
+	database.execute("create table if not exists render_counts (user integer not null, guild integer not null, count integer not null, unique (user, guild)) strict", []).unwrap();
+	database
+}
+
+/// Makes the client used by `?render-url`.
+fn http_client() -> reqwest::Client {
+	reqwest::Client::builder()
+		.timeout(FETCH_TIMEOUT)
+		// A redirect could lead to a host that isn't allowed.
+		.redirect(reqwest::redirect::Policy::none())
+		.user_agent(concat!("typst-bot/", env!("CARGO_PKG_VERSION")))
+		.build()
+		.expect("failed to create the HTTP client")
+}
+
+/// The most attachments that Discord allows on a message.
+const DISCORD_MAX_ATTACHMENTS: usize = 10;
+
+/// Reads `MAX_ATTACHMENTS`, defaulting to Discord's limit.
+fn max_attachments() -> usize {
+	std::env::var("MAX_ATTACHMENTS").map_or(DISCORD_MAX_ATTACHMENTS, |value| {
+		value
+			.parse()
+			.ok()
+			.filter(|max| (1..=DISCORD_MAX_ATTACHMENTS).contains(max))
+			.unwrap_or_else(|| {
+				panic!("`MAX_ATTACHMENTS` must be an integer from 1 to {DISCORD_MAX_ATTACHMENTS}")
+			})
+	})
+}
+
+/// Reads `WORKER_RETRIES`, defaulting to retrying once.
+fn worker_retries() -> u32 {
+	std::env::var("WORKER_RETRIES").map_or(1, |value| {
+		value
+			.parse()
+			.expect("`WORKER_RETRIES` must be a non-negative integer")
+	})
+}
+
+/// Reads the `WORKER_IDLE_TIMEOUT` env var, if it's set.
+fn idle_timeout() -> Option<Duration> {
+	seconds_env_var("WORKER_IDLE_TIMEOUT")
+}
+
+/// Reads the `WORKER_WATCHDOG_INTERVAL` env var, if it's set.
+fn watchdog_interval() -> Option<Duration> {
+	seconds_env_var("WORKER_WATCHDOG_INTERVAL")
+}
+
+/// Reads an env var that's a positive number of seconds, if it's set.
+fn seconds_env_var(name: &str) -> Option<Duration> {
+	let value = std::env::var_os(name)?;
+	let seconds = value
+		.to_str()
+		.and_then(|seconds| seconds.parse().ok())
+		.filter(|&seconds| seconds > 0)
+		.unwrap_or_else(|| panic!("`{name}` must be a positive number of seconds"));
+	Some(Duration::from_secs(seconds))
+}
+
+pub async fn run() {
+	let database = std::sync::Mutex::new(open_database());
+
+	let pool = Arc::new(Mutex::new(Worker::spawn(worker_retries()).await.unwrap()));
+	spawn_worker_tasks(&pool);
 
-	let pool = Worker::spawn().await.unwrap();
+	let edit_tracker_time = Duration::from_hours(1);
 
-	let edit_tracker_time = std::time::Duration::from_secs(3600);
+	let max_attachments = max_attachments();
+
+	let http = http_client();
+
+	let error_notice = std::env::var("ERROR_NOTICE")
+		.ok()
+		.filter(|notice| !notice.is_empty());
+
+	let mut commands = vec![
+		render(),
+		render_url(),
+		code(),
+		theme_preview(),
+		diff(),
+		help(),
+		source(),
+		example(),
+		template(),
+		explain(),
+		history(),
+		again(),
+		ast(),
+		debug(),
+		outline(),
+		textwidth(),
+		pdfinfo(),
+		manifest(),
+		version(),
+		escape(),
+		preamble(),
+		limits(),
+		packages(),
+		reload_fonts(),
+		config(),
+		raw_request(),
+		blacklist(),
+		tag(),
+		tag_raw(),
+		set_tag(),
+		delete_tag(),
+		set_tag_role(),
+		list_tags(),
+		toggle_preamble(),
+		set_guild_preamble(),
+		set_language(),
+		leaderboard(),
+	];
+	if let Ok(aliases) = std::env::var("COMMAND_ALIASES") {
+		add_aliases(&mut commands, &aliases);
+	}
 
 	let token = std::env::var("DISCORD_TOKEN").expect("need `DISCORD_TOKEN` env var");
 	let intents = GatewayIntents::non_privileged() | GatewayIntents::MESSAGE_CONTENT;
@@ -776,17 +4425,8 @@ pub async fn run() {
 				edit_tracker: Some(poise::EditTracker::for_timespan(edit_tracker_time).into()),
 				..Default::default()
 			},
-			commands: vec![
-				render(),
-				help(),
-				source(),
-				ast(),
-				version(),
-				tag(),
-				set_tag(),
-				delete_tag(),
-				list_tags(),
-			],
+			commands,
+			command_check: Some(|ctx| Box::pin(async move { not_blacklisted(ctx) })),
 			allowed_mentions: Some(CreateAllowedMentions::new()),
 			on_error: |error| {
 				Box::pin(async move {
@@ -797,12 +4437,17 @@ pub async fn run() {
 			},
 			..Default::default()
 		})
-		.setup(|ctx, _ready, framework| {
+		.setup(move |ctx, _ready, framework| {
 			Box::pin(async move {
 				poise::builtins::register_globally(ctx, &framework.options().commands).await?;
 				Ok(Data {
-					pool: Mutex::new(pool),
+					pool,
 					database,
+					http,
+					error_notice,
+					max_attachments,
+					last_errors: std::sync::Mutex::new(HashMap::new()),
+					history: std::sync::Mutex::new(History::default()),
 				})
 			})
 		})
@@ -0,0 +1,133 @@
+/// Pairs of standard library function names and example code for `?example`, in alphabetical order.
+pub const EXAMPLES: &[(&str, &str)] = &[
+	(
+		"align",
+		r"#align(center)[Centered]
+#align(right)[Right-aligned]
+#align(center + horizon, rect(width: 100%, height: 40pt)[Middle])",
+	),
+	(
+		"box",
+		r"Text with #box(fill: aqua, inset: 2pt, radius: 2pt)[a box] inline,
+and a #box(baseline: 20%, square(size: 8pt, fill: red)) square.",
+	),
+	(
+		"columns",
+		r"#columns(2)[
+  #lorem(20)
+  #colbreak()
+  #lorem(15)
+]",
+	),
+	(
+		"enum",
+		r#"+ First
++ Second
+  + Nested
++ Third
+
+#enum(numbering: "a)", [Alpha], [Beta])"#,
+	),
+	(
+		"figure",
+		r"#figure(
+  rect(width: 80pt, height: 40pt, fill: gradient.linear(..color.map.viridis)),
+  caption: [A gradient.],
+) <grad>
+
+@grad shows a gradient.",
+	),
+	(
+		"footnote",
+		r"#set page(height: 120pt)
+Typst is a markup language#footnote[And a compiler.] for typesetting.",
+	),
+	(
+		"grid",
+		r"#grid(
+  columns: (1fr, 2fr),
+  gutter: 4pt,
+  rect(width: 100%)[1fr],
+  rect(width: 100%)[2fr],
+  grid.cell(colspan: 2, rect(width: 100%)[Both]),
+)",
+	),
+	(
+		"heading",
+		r#"#set heading(numbering: "1.a")
+= Introduction
+== Background
+== Goals
+= Methods"#,
+	),
+	(
+		"highlight",
+		r"This is #highlight[important] and #highlight(fill: aqua)[this too].",
+	),
+	(
+		"list",
+		r"- Apples
+- Oranges
+  - Blood oranges
+- Pears",
+	),
+	("lorem", r"#lorem(30)"),
+	(
+		"math.equation",
+		r#"#set math.equation(numbering: "(1)")
+$ sum_(k=1)^n k = (n(n+1)) / 2 $
+Inline math like $a^2 + b^2 = c^2$ works too."#,
+	),
+	(
+		"outline",
+		r"#outline()
+= Introduction
+== Motivation
+= Conclusion",
+	),
+	(
+		"raw",
+		r#"```rust
+fn main() {
+    println!("Hello, world!");
+}
+```"#,
+	),
+	(
+		"rect",
+		r"#rect(width: 60pt, height: 30pt, fill: teal, stroke: 2pt + navy, radius: 4pt)",
+	),
+	(
+		"stack",
+		r"#stack(
+  dir: ltr,
+  spacing: 4pt,
+  square(size: 20pt, fill: red),
+  square(size: 20pt, fill: green),
+  square(size: 20pt, fill: blue),
+)",
+	),
+	(
+		"table",
+		r"#table(
+  columns: 3,
+  table.header[*Name*][*Qty*][*Price*],
+  [Apple], [3], [\$1.50],
+  [Pear], [2], [\$2.00],
+)",
+	),
+	(
+		"text",
+		r#"#text(size: 14pt, fill: maroon)[Big red text]
+#text(weight: "bold", style: "italic")[Bold italic]
+#text(tracking: 2pt)[Spaced out]"#,
+	),
+];
+
+/// Finds the example for the function, ignoring case.
+pub fn find(function: &str) -> Option<&'static str> {
+	EXAMPLES
+		.iter()
+		.find(|(name, _)| name.eq_ignore_ascii_case(function))
+		.map(|&(_, code)| code)
+}
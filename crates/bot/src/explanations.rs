@@ -0,0 +1,66 @@
+/// Pairs of text found in error messages and plain-language explanations of them for `?explain`.
+const EXPLANATIONS: &[(&str, &str)] = &[
+	(
+		"unknown variable",
+		"**Unknown variable:** a name was used that isn't defined. Check its spelling, define it with `#let name = ...` before using it, or import it if it comes from a package, like `#import \"@preview/cetz:0.3.1\": canvas`. In markup, `#` switches to code, so `#x` looks up a variable named `x`.",
+	),
+	(
+		", found ",
+		"**Type mismatch:** a function got a different kind of value than it expects. For example, lengths need units, so write `10pt` rather than `10`, and text is written in quotes, like `\"hello\"`. Use `str(...)`, `int(...)`, or `float(...)` to convert between types.",
+	),
+	(
+		"unresolved import",
+		"**Unresolved import:** the module doesn't have an item with that name. Check the spelling, and check the documentation of the package for what it provides.",
+	),
+	(
+		"package not found",
+		"**Package not found:** packages are imported with their namespace and version, like `#import \"@preview/cetz:0.3.1\"`. Check the name and version on <https://typst.app/universe>.",
+	),
+	(
+		"file not found",
+		"**File not found:** the bot can't read your own files, such as images or other `.typ` files, so only code in the message (and packages) can be used. For trying things out, the bundled `example.png` and `example.csv` are available, like `#image(\"example.png\")` or `#csv(\"example.csv\")`.",
+	),
+	(
+		"unclosed delimiter",
+		"**Unclosed delimiter:** a `(`, `[`, `{`, or `\"` was opened but never closed. Check that every one has a matching closing one.",
+	),
+	(
+		"unexpected argument",
+		"**Unexpected argument:** a function was given an argument it doesn't accept. Check the function's documentation for the names of its parameters.",
+	),
+	(
+		"missing argument",
+		"**Missing argument:** a function needs an argument that wasn't given. Check the function's documentation for its required parameters.",
+	),
+	(
+		"cannot add",
+		"**Cannot add:** `+` only works between compatible values, like two numbers, two strings, or two pieces of content. Convert one side first, like `str(1) + \"a\"`.",
+	),
+	(
+		"does not contain field",
+		"**Missing field:** the value doesn't have a field with that name. Use `#repr(value)` to see what the value is, and check the documentation of its type for its fields.",
+	),
+	(
+		"maximum function call depth exceeded",
+		"**Too much recursion:** a function kept calling itself, most likely without a case that stops it. This can also happen with a show rule whose output matches its own selector.",
+	),
+];
+
+/// Finds the explanations for each recognized part of the error, in the order of [`EXPLANATIONS`].
+pub fn find(error: &str) -> Vec<&'static str> {
+	EXPLANATIONS
+		.iter()
+		.filter(|(pattern, _)| error.contains(pattern))
+		.map(|&(_, explanation)| explanation)
+		.collect()
+}
+
+#[test]
+fn test_find() {
+	assert_eq!(find("error: unknown variable: foo").len(), 1);
+	assert_eq!(
+		find("error: expected length, found integer"),
+		[EXPLANATIONS[1].1],
+	);
+	assert!(find("error: something else").is_empty());
+}
@@ -0,0 +1,120 @@
+use std::collections::{HashMap, VecDeque};
+use std::time::{Duration, Instant};
+
+use serenity::model::id::UserId;
+
+/// The most renders kept for each user.
+const MAX_ENTRIES: usize = 10;
+/// How long renders are kept.
+pub const MAX_AGE: Duration = Duration::from_hours(1);
+/// The most users whose renders are kept.
+const MAX_USERS: usize = 1000;
+
+/// A render kept for `?history` and `?again`.
+pub struct Entry<T> {
+	/// Numbers each of the user's renders, starting at 1.
+	pub id: u64,
+	pub time: Instant,
+	pub render: T,
+}
+
+struct UserHistory<T> {
+	next_id: u64,
+	/// Oldest first.
+	entries: VecDeque<Entry<T>>,
+}
+
+/// The recent renders of each user, kept only in memory and only briefly.
+pub struct History<T> {
+	users: HashMap<UserId, UserHistory<T>>,
+}
+
+impl<T> Default for History<T> {
+	fn default() -> Self {
+		Self {
+			users: HashMap::new(),
+		}
+	}
+}
+
+impl<T> History<T> {
+	/// Keeps the render, returning its id.
+	pub fn record(&mut self, user: UserId, render: T, now: Instant) -> u64 {
+		if self.users.len() >= MAX_USERS && !self.users.contains_key(&user) {
+			self.users.retain(|_, history| {
+				history
+					.entries
+					.back()
+					.is_some_and(|entry| now.duration_since(entry.time) < MAX_AGE)
+			});
+			if self.users.len() >= MAX_USERS {
+				self.users.clear();
+			}
+		}
+
+		let history = self.users.entry(user).or_insert(UserHistory {
+			next_id: 1,
+			entries: VecDeque::new(),
+		});
+		let id = history.next_id;
+		history.next_id += 1;
+		history.entries.push_back(Entry {
+			id,
+			time: now,
+			render,
+		});
+		if history.entries.len() > MAX_ENTRIES {
+			history.entries.pop_front();
+		}
+		id
+	}
+
+	/// The user's renders that haven't expired, oldest first.
+	pub fn entries(&mut self, user: UserId, now: Instant) -> impl Iterator<Item = &Entry<T>> {
+		let entries = self.users.get_mut(&user).map(|history| {
+			history
+				.entries
+				.retain(|entry| now.duration_since(entry.time) < MAX_AGE);
+			&history.entries
+		});
+		entries.into_iter().flatten()
+	}
+
+	pub fn get(&mut self, user: UserId, id: u64, now: Instant) -> Option<&T> {
+		self
+			.entries(user, now)
+			.find(|entry| entry.id == id)
+			.map(|entry| &entry.render)
+	}
+}
+
+#[test]
+fn test_history() {
+	let start = Instant::now();
+	let (alice, bob) = (UserId::new(1), UserId::new(2));
+	let mut history = History::default();
+
+	for i in 0..12 {
+		history.record(alice, i, start);
+	}
+	assert_eq!(history.record(bob, 100, start), 1);
+
+	let ids = |history: &mut History<_>, user, now| {
+		history
+			.entries(user, now)
+			.map(|entry| entry.id)
+			.collect::<Vec<_>>()
+	};
+	assert_eq!(
+		ids(&mut history, alice, start),
+		(3..=12).collect::<Vec<_>>()
+	);
+	assert_eq!(history.get(alice, 12, start), Some(&11));
+	assert_eq!(history.get(alice, 1, start), None);
+	assert_eq!(history.get(bob, 1, start), Some(&100));
+
+	let later = start + MAX_AGE;
+	history.record(alice, 12, later);
+	assert_eq!(ids(&mut history, alice, later), [13]);
+	assert!(history.get(bob, 1, later).is_none());
+}
@@ -0,0 +1,238 @@
+/// A language for the bot's messages, chosen per guild with `?set-language`.
+///
+/// Only the most common messages, those of rendering and errors, are translated so far; the rest are in English.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Locale {
+	#[default]
+	English,
+	German,
+	Spanish,
+	French,
+}
+
+impl Locale {
+	pub const ALL: &'static [Self] = &[Self::English, Self::German, Self::Spanish, Self::French];
+
+	/// The ISO 639-1 code, which is how the locale is written in commands and stored.
+	pub const fn code(self) -> &'static str {
+		match self {
+			Self::English => "en",
+			Self::German => "de",
+			Self::Spanish => "es",
+			Self::French => "fr",
+		}
+	}
+
+	pub fn from_code(code: &str) -> Option<Self> {
+		Self::ALL
+			.iter()
+			.copied()
+			.find(|locale| locale.code().eq_ignore_ascii_case(code))
+	}
+
+	/// The name of the language in itself.
+	pub const fn name(self) -> &'static str {
+		match self {
+			Self::English => "English",
+			Self::German => "Deutsch",
+			Self::Spanish => "Español",
+			Self::French => "Français",
+		}
+	}
+
+	pub const fn error_occurred(self) -> &'static str {
+		match self {
+			Self::English => "An error occurred",
+			Self::German => "Ein Fehler ist aufgetreten",
+			Self::Spanish => "Se produjo un error",
+			Self::French => "Une erreur s'est produite",
+		}
+	}
+
+	pub const fn note(self) -> &'static str {
+		match self {
+			Self::English => "Note",
+			Self::German => "Hinweis",
+			Self::Spanish => "Nota",
+			Self::French => "Remarque",
+		}
+	}
+
+	pub const fn no_pages(self) -> &'static str {
+		match self {
+			Self::English => "no pages generated",
+			Self::German => "keine Seiten erzeugt",
+			Self::Spanish => "no se generaron páginas",
+			Self::French => "aucune page générée",
+		}
+	}
+
+	pub fn more_pages_ignored(self, count: usize) -> String {
+		match (self, count) {
+			(Self::English, 1) => "1 more page ignored".into(),
+			(Self::English, _) => format!("{count} more pages ignored"),
+			(Self::German, 1) => "1 weitere Seite ignoriert".into(),
+			(Self::German, _) => format!("{count} weitere Seiten ignoriert"),
+			(Self::Spanish, 1) => "se ignoró 1 página más".into(),
+			(Self::Spanish, _) => format!("se ignoraron {count} páginas más"),
+			(Self::French, 1) => "1 page supplémentaire ignorée".into(),
+			(Self::French, _) => format!("{count} pages supplémentaires ignorées"),
+		}
+	}
+
+	pub fn primary_not_rendered(self, page: usize) -> String {
+		match self {
+			Self::English => format!("page {page} was not rendered, so it can't be shown first"),
+			Self::German => {
+				format!("Seite {page} wurde nicht gerendert und kann daher nicht zuerst angezeigt werden")
+			}
+			Self::Spanish => {
+				format!("la página {page} no se renderizó, así que no se puede mostrar primero")
+			}
+			Self::French => {
+				format!("la page {page} n'a pas été rendue, elle ne peut donc pas être affichée en premier")
+			}
+		}
+	}
+
+	pub const fn worker_restarted(self) -> &'static str {
+		match self {
+			Self::English => "the worker crashed and was restarted",
+			Self::German => "der Worker ist abgestürzt und wurde neu gestartet",
+			Self::Spanish => "el worker falló y se reinició",
+			Self::French => "le worker a planté et a été redémarré",
+		}
+	}
+
+	pub fn page_timed_out(self, page: usize) -> String {
+		match self {
+			Self::English => format!(
+				"page {page} took too long to render, so it and any pages after it were skipped"
+			),
+			Self::German => format!(
+				"Seite {page} brauchte zu lange zum Rendern, daher wurden sie und alle folgenden Seiten übersprungen"
+			),
+			Self::Spanish => format!(
+				"la página {page} tardó demasiado en renderizarse, así que se omitieron ella y las siguientes"
+			),
+			Self::French => format!(
+				"le rendu de la page {page} a pris trop de temps, elle et les pages suivantes ont donc été ignorées"
+			),
+		}
+	}
+
+	pub const fn compressed_as_jpeg(self) -> &'static str {
+		match self {
+			Self::English => "some pages were too large as PNGs, so they were compressed as JPEGs",
+			Self::German => "einige Seiten waren als PNG zu groß und wurden daher als JPEG komprimiert",
+			Self::Spanish => {
+				"algunas páginas eran demasiado grandes como PNG, así que se comprimieron como JPEG"
+			}
+			Self::French => {
+				"certaines pages étaient trop volumineuses en PNG, elles ont donc été compressées en JPEG"
+			}
+		}
+	}
+
+	pub fn attachments_omitted(self, count: usize, max: usize) -> String {
+		match (self, count) {
+			(Self::English, 1) => {
+				format!("1 more file was left out to stay within the limit of {max} attachments")
+			}
+			(Self::English, _) => {
+				format!("{count} more files were left out to stay within the limit of {max} attachments")
+			}
+			(Self::German, 1) => {
+				format!("1 weitere Datei wurde weggelassen, um das Limit von {max} Anhängen einzuhalten")
+			}
+			(Self::German, _) => format!(
+				"{count} weitere Dateien wurden weggelassen, um das Limit von {max} Anhängen einzuhalten"
+			),
+			(Self::Spanish, 1) => {
+				format!("se omitió 1 archivo más para no superar el límite de {max} adjuntos")
+			}
+			(Self::Spanish, _) => {
+				format!("se omitieron {count} archivos más para no superar el límite de {max} adjuntos")
+			}
+			(Self::French, 1) => {
+				format!(
+					"1 fichier supplémentaire a été omis pour respecter la limite de {max} pièces jointes"
+				)
+			}
+			(Self::French, _) => format!(
+				"{count} fichiers supplémentaires ont été omis pour respecter la limite de {max} pièces jointes"
+			),
+		}
+	}
+
+	pub const fn pages(self) -> &'static str {
+		match self {
+			Self::English | Self::French => "Pages",
+			Self::German => "Seiten",
+			Self::Spanish => "Páginas",
+		}
+	}
+
+	pub const fn succeeded_with_warnings(self) -> &'static str {
+		match self {
+			Self::English => "Render succeeded with warnings",
+			Self::German => "Rendern mit Warnungen erfolgreich",
+			Self::Spanish => "Renderizado completado con advertencias",
+			Self::French => "Rendu réussi avec des avertissements",
+		}
+	}
+
+	pub const fn progress(self) -> &'static str {
+		match self {
+			Self::English => "Progress",
+			Self::German => "Fortschritt",
+			Self::Spanish => "Progreso",
+			Self::French => "Progression",
+		}
+	}
+
+	pub fn downloading(self, spec: &str) -> String {
+		match self {
+			Self::English => format!("Downloading {spec}"),
+			Self::German => format!("Lade {spec} herunter"),
+			Self::Spanish => format!("Descargando {spec}"),
+			Self::French => format!("Téléchargement de {spec}"),
+		}
+	}
+
+	pub const fn compiling(self) -> &'static str {
+		match self {
+			Self::English => "Compiling",
+			Self::German => "Kompiliere",
+			Self::Spanish => "Compilando",
+			Self::French => "Compilation",
+		}
+	}
+
+	pub fn rendering(self, page: usize, total: usize) -> String {
+		match self {
+			Self::English => format!("Rendering page {page} of {total}"),
+			Self::German => format!("Rendere Seite {page} von {total}"),
+			Self::Spanish => format!("Renderizando página {page} de {total}"),
+			Self::French => format!("Rendu de la page {page} sur {total}"),
+		}
+	}
+
+	pub const fn tag_not_defined(self) -> &'static str {
+		match self {
+			Self::English => "That tag is not defined.",
+			Self::German => "Dieser Tag ist nicht definiert.",
+			Self::Spanish => "Esa etiqueta no está definida.",
+			Self::French => "Ce tag n'est pas défini.",
+		}
+	}
+}
+
+#[test]
+fn test_locale_codes() {
+	for &locale in Locale::ALL {
+		assert_eq!(Locale::from_code(locale.code()), Some(locale));
+	}
+	assert_eq!(Locale::from_code("DE"), Some(Locale::German));
+	assert_eq!(Locale::from_code("xx"), None);
+}
@@ -15,6 +15,10 @@
 #![forbid(unsafe_code)]
 
 mod bot;
+mod examples;
+mod explanations;
+mod history;
+mod locale;
 mod worker;
 
 const SOURCE_URL: &str = "https://github.com/mattfbacon/typst-bot";
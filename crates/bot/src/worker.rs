@@ -1,45 +1,147 @@
 use std::pin::pin;
-use std::process::{Child, Stdio};
+use std::process::{Child, ChildStdin, ChildStdout, Stdio};
 use std::time::Duration;
 
 use anyhow::{anyhow, bail, Context as _};
-use protocol::{Request, Response};
+use protocol::{Progress, RenderOptions, Request, Response};
 use tokio::select;
 use tokio::sync::mpsc;
 use tokio::time::Instant;
 
+/// How long the worker may go without sending a progress message before it is killed.
+pub const PROGRESS_TIMEOUT: Duration = Duration::from_secs(5);
+/// How long the worker may spend on a request in total before it is killed, for the smallest code.
+pub const BASE_TOTAL_TIMEOUT: Duration = Duration::from_secs(20);
+/// The extra time allowed for each KiB of code.
+pub const TOTAL_TIMEOUT_PER_KIB: Duration = Duration::from_secs(2);
+/// How long the worker may spend on any request, no matter how long the code is.
+pub const MAX_TOTAL_TIMEOUT: Duration = Duration::from_secs(45);
+/// How long the worker may take to answer the watchdog's health check before it's restarted.
+pub const HEALTH_CHECK_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The total timeout for a request with the given length of code, in bytes,
+/// so that small snippets fail fast while larger documents get more room.
+pub fn total_timeout(code_length: usize) -> Duration {
+	let kib = u32::try_from(code_length / 1024).unwrap_or(u32::MAX);
+	BASE_TOTAL_TIMEOUT
+		.saturating_add(TOTAL_TIMEOUT_PER_KIB.saturating_mul(kib))
+		.min(MAX_TOTAL_TIMEOUT)
+}
+
+/// The worker crashing or timing out, rather than the request failing on its own,
+/// which means that trying again may work.
+#[derive(Debug, thiserror::Error)]
+#[error("{0}")]
+pub struct WorkerFailure(String);
+
+#[test]
+fn test_total_timeout() {
+	assert_eq!(total_timeout(0), BASE_TOTAL_TIMEOUT);
+	assert_eq!(total_timeout(1023), BASE_TOTAL_TIMEOUT);
+	assert_eq!(
+		total_timeout(3 * 1024),
+		BASE_TOTAL_TIMEOUT + 3 * TOTAL_TIMEOUT_PER_KIB
+	);
+	assert_eq!(total_timeout(usize::MAX), MAX_TOTAL_TIMEOUT);
+}
+
+/// What to render.
+pub enum RenderInput {
+	Code(String),
+	/// The package specification of a template package, like `@preview/example:0.1.0`.
+	Template(String),
+}
+
 #[derive(Debug)]
 pub struct Worker {
-	process: Process,
+	/// This is `None` while the worker is stopped for being idle.
+	process: Option<Process>,
+	last_used: Instant,
+	/// How many times a request is retried after the worker crashes on it.
+	retries: u32,
 }
 
 impl Worker {
-	pub async fn spawn() -> anyhow::Result<Self> {
+	pub async fn spawn(retries: u32) -> anyhow::Result<Self> {
 		Ok(Self {
-			process: Process::spawn().await?,
+			process: Some(Process::spawn().await?),
+			last_used: Instant::now(),
+			retries,
 		})
 	}
 
+	/// Stops the worker process if it hasn't been used for `idle_timeout`, to save resources.
+	/// It's started again when it's next needed.
+	pub async fn stop_if_idle(&mut self, idle_timeout: Duration) -> anyhow::Result<()> {
+		if self.last_used.elapsed() < idle_timeout {
+			return Ok(());
+		}
+		if let Some(process) = self.process.take() {
+			tracing::info!("Stopping idle worker");
+			process.kill().await?;
+		}
+		Ok(())
+	}
+
+	/// Restarts the worker process if it doesn't answer a health check within [`HEALTH_CHECK_TIMEOUT`],
+	/// so that a wedged worker is replaced before a request runs into it.
+	/// A stopped worker is left stopped, and the check doesn't count as using the worker.
+	pub async fn check_health(&mut self) -> anyhow::Result<()> {
+		let Some(process) = &mut self.process else {
+			return Ok(());
+		};
+		let res = tokio::time::timeout(
+			HEALTH_CHECK_TIMEOUT,
+			process.communicate(Request::Version, None),
+		)
+		.await;
+		let error = match res {
+			Ok(Ok(_)) => return Ok(()),
+			Ok(Err(error)) => error,
+			Err(_) => anyhow!("no response within {}s", HEALTH_CHECK_TIMEOUT.as_secs()),
+		};
+		tracing::warn!(?error, "Restarting worker that failed its health check");
+		process.replace().await
+	}
+
+	/// Returns the worker process, starting it if it was stopped.
+	async fn process(&mut self) -> anyhow::Result<&mut Process> {
+		if self.process.is_none() {
+			self.process = Some(Process::spawn().await?);
+		}
+		Ok(self.process.as_mut().unwrap())
+	}
+
+	/// Sends the request to the worker, restarting it and retrying up to `retries` times if it crashes.
+	///
+	/// If a retry succeeds, the error from the crash is returned along with the response.
 	async fn run(
 		&mut self,
 		request: Request,
-		progress_channel_outer: Option<mpsc::Sender<String>>,
-	) -> anyhow::Result<Response> {
-		struct Timeout;
+		progress_channel_outer: Option<mpsc::Sender<Progress>>,
+		long_timeout: Duration,
+	) -> anyhow::Result<(Response, Option<anyhow::Error>)> {
+		enum Timeout {
+			Progress,
+			Total,
+		}
 
 		// This timeout is reset any time a progress message is received.
-		let fast_timeout = Duration::from_secs(5);
-		// This is a universal timeout that is never reset.
-		let long_timeout = Duration::from_secs(30);
-		let mut tries_left = 2;
+		let fast_timeout = PROGRESS_TIMEOUT;
+		// `long_timeout` is a universal timeout that is never reset.
+		let mut retries_left = self.retries;
+		let mut crash = None;
+		self.last_used = Instant::now();
 
 		loop {
+			let process = self.process().await?;
 			let (progress_inner_send, mut progress_inner_recv) = mpsc::channel(1);
+			// Progress that didn't fit in the outer channel, replaced by any newer progress,
+			// so that a slow consumer gets the latest state without holding up the worker.
+			let mut pending = None;
 
 			let res = {
-				let mut fut = pin!(self
-					.process
-					.communicate(request.clone(), Some(progress_inner_send)));
+				let mut fut = pin!(process.communicate(request.clone(), Some(progress_inner_send)));
 				let mut fast_timeout_fut = pin!(tokio::time::sleep(fast_timeout));
 				let mut long_timeout_fut = pin!(tokio::time::sleep(long_timeout));
 				loop {
@@ -50,84 +152,254 @@ impl Worker {
 						Some(progress) = progress_inner_recv.recv() => {
 							fast_timeout_fut.as_mut().reset(Instant::now() + fast_timeout);
 							if let Some(outer) = &progress_channel_outer {
-								_ = outer.send(progress).await;
+								// Sending straight away while older progress is still pending would deliver them out of order.
+								if pending.is_some() {
+									pending = Some(progress);
+								} else if let Err(mpsc::error::TrySendError::Full(progress)) = outer.try_send(progress) {
+									pending = Some(progress);
+								}
 							}
 						}
+						Some(permit) = reserve(progress_channel_outer.as_ref()), if pending.is_some() => {
+							permit.send(pending.take().unwrap());
+						}
 						() = fast_timeout_fut.as_mut() => {
-							break Err(Timeout);
+							break Err(Timeout::Progress);
 						}
 						() = long_timeout_fut.as_mut() => {
-							break Err(Timeout);
+							break Err(Timeout::Total);
 						}
 					};
 				}
 			};
 
+			if let (Some(outer), Some(progress)) = (&progress_channel_outer, pending) {
+				_ = outer.send(progress).await;
+			}
+
 			let error = match res {
-				Ok(Ok(response)) => return Ok(response),
+				Ok(Ok(response)) => return Ok((response, crash)),
 				Ok(Err(error)) => {
-					self.process.replace().await?;
+					process.replace().await?;
 					error
 				}
-				Err(Timeout) => {
-					self.process.replace().await?;
-					bail!("timeout");
+				Err(timeout) => {
+					process.replace().await?;
+					let problem = match timeout {
+						Timeout::Progress => format!(
+							"the request made no progress for {}s, which is the limit",
+							fast_timeout.as_secs(),
+						),
+						Timeout::Total => {
+							format!("the request exceeded the {}s limit", long_timeout.as_secs())
+						}
+					};
+					return Err(
+						WorkerFailure(format!(
+							"{problem}; try simplifying the document (see `?limits` for all limits)"
+						))
+						.into(),
+					);
 				}
 			};
 
-			tries_left -= 1;
-			if tries_left == 0 {
-				return Err(error);
+			if retries_left == 0 {
+				let context = match self.retries {
+					0 => "the worker crashed and was restarted".to_owned(),
+					1 => "the worker crashed and was restarted, then crashed again on retry".to_owned(),
+					retries => format!(
+						"the worker crashed and was restarted, then crashed again on all {retries} retries"
+					),
+				};
+				return Err(error.context(WorkerFailure(context)));
 			}
+			retries_left -= 1;
+			crash = Some(error);
 		}
 	}
 
 	pub async fn render(
 		&mut self,
-		code: String,
-		progress_channel: mpsc::Sender<String>,
-	) -> anyhow::Result<protocol::Rendered> {
-		let response = self
-			.run(Request::Render { code }, Some(progress_channel))
-			.await?;
+		input: RenderInput,
+		options: RenderOptions,
+		progress_channel: mpsc::Sender<Progress>,
+	) -> anyhow::Result<(protocol::Rendered, Option<anyhow::Error>)> {
+		let (request, timeout) = match input {
+			RenderInput::Code(code) => {
+				let timeout = total_timeout(code.len());
+				(Request::Render { code, options }, timeout)
+			}
+			// The template's length isn't known until it's downloaded, so it gets the most time.
+			RenderInput::Template(spec) => (Request::Template { spec, options }, MAX_TOTAL_TIMEOUT),
+		};
+		let (response, crash) = self.run(request, Some(progress_channel), timeout).await?;
 		let Response::Render(response) = response else {
 			bail!("expected Render response, got {response:?}");
 		};
-		response.map_err(|error| anyhow!(error))
+		response
+			.map(|rendered| (rendered, crash))
+			.map_err(|error| anyhow!(error))
 	}
 
-	pub async fn ast(&mut self, code: String) -> anyhow::Result<protocol::AstResponse> {
-		let response = self.run(Request::Ast { code }, None).await?;
+	pub async fn ast(&mut self, code: String, spans: bool) -> anyhow::Result<String> {
+		let timeout = total_timeout(code.len());
+		let (response, _) = self
+			.run(Request::Ast { code, spans }, None, timeout)
+			.await?;
 		let Response::Ast(response) = response else {
 			bail!("expected Ast response, got {response:?}");
 		};
+		response.map_err(|error| anyhow!(error))
+	}
+
+	pub async fn pdf_info(&mut self, code: String) -> anyhow::Result<protocol::PdfInfo> {
+		let timeout = total_timeout(code.len());
+		let (response, _) = self.run(Request::PdfInfo { code }, None, timeout).await?;
+		let Response::PdfInfo(response) = response else {
+			bail!("expected PdfInfo response, got {response:?}");
+		};
+		response.map_err(|error| anyhow!(error))
+	}
+
+	pub async fn outline(&mut self, code: String) -> anyhow::Result<Vec<protocol::OutlineEntry>> {
+		let timeout = total_timeout(code.len());
+		let (response, _) = self.run(Request::Outline { code }, None, timeout).await?;
+		let Response::Outline(response) = response else {
+			bail!("expected Outline response, got {response:?}");
+		};
+		response.map_err(|error| anyhow!(error))
+	}
+
+	pub async fn manifest(&mut self, manifest: String) -> anyhow::Result<protocol::ManifestResponse> {
+		let timeout = total_timeout(manifest.len());
+		let (response, _) = self
+			.run(Request::Manifest { manifest }, None, timeout)
+			.await?;
+		let Response::Manifest(response) = response else {
+			bail!("expected Manifest response, got {response:?}");
+		};
 		Ok(response)
 	}
 
+	pub async fn diff(
+		&mut self,
+		before: String,
+		after: String,
+	) -> anyhow::Result<protocol::DiffImage> {
+		let timeout = total_timeout(before.len() + after.len());
+		let (response, _) = self
+			.run(Request::Diff { before, after }, None, timeout)
+			.await?;
+		let Response::Diff(response) = response else {
+			bail!("expected Diff response, got {response:?}");
+		};
+		response.map_err(|error| anyhow!(error))
+	}
+
+	pub async fn measure(
+		&mut self,
+		text: String,
+		font: Option<String>,
+		size: Option<f64>,
+	) -> anyhow::Result<protocol::TextSize> {
+		let timeout = total_timeout(text.len());
+		let (response, _) = self
+			.run(Request::Measure { text, font, size }, None, timeout)
+			.await?;
+		let Response::Measure(response) = response else {
+			bail!("expected Measure response, got {response:?}");
+		};
+		response.map_err(|error| anyhow!(error))
+	}
+
+	pub async fn debug(&mut self, code: String) -> anyhow::Result<protocol::DebugInfo> {
+		let timeout = total_timeout(code.len());
+		let (response, _) = self.run(Request::Debug { code }, None, timeout).await?;
+		let Response::Debug(response) = response else {
+			bail!("expected Debug response, got {response:?}");
+		};
+		response.map_err(|error| anyhow!(error))
+	}
+
 	pub async fn version(&mut self) -> anyhow::Result<protocol::VersionResponse> {
-		let response = self.run(Request::Version, None).await?;
+		let (response, _) = self.run(Request::Version, None, BASE_TOTAL_TIMEOUT).await?;
 		let Response::Version(response) = response else {
 			bail!("expected Version response, got {response:?}");
 		};
 		Ok(response)
 	}
+
+	pub async fn list_packages(&mut self) -> anyhow::Result<protocol::ListPackagesResponse> {
+		let (response, _) = self
+			.run(Request::ListPackages, None, BASE_TOTAL_TIMEOUT)
+			.await?;
+		let Response::ListPackages(response) = response else {
+			bail!("expected ListPackages response, got {response:?}");
+		};
+		Ok(response)
+	}
+
+	pub async fn limits(&mut self) -> anyhow::Result<protocol::LimitsResponse> {
+		let (response, _) = self.run(Request::Limits, None, BASE_TOTAL_TIMEOUT).await?;
+		let Response::Limits(response) = response else {
+			bail!("expected Limits response, got {response:?}");
+		};
+		Ok(response)
+	}
+
+	pub async fn config(&mut self) -> anyhow::Result<protocol::ConfigResponse> {
+		let (response, _) = self.run(Request::Config, None, BASE_TOTAL_TIMEOUT).await?;
+		let Response::Config(response) = response else {
+			bail!("expected Config response, got {response:?}");
+		};
+		Ok(response)
+	}
+
+	/// Sends any request and returns the response as is, for debugging the protocol.
+	pub async fn raw(&mut self, request: Request) -> anyhow::Result<Response> {
+		let (response, _) = self.run(request, None, MAX_TOTAL_TIMEOUT).await?;
+		Ok(response)
+	}
+
+	pub async fn reload_fonts(&mut self) -> anyhow::Result<protocol::ReloadedFonts> {
+		let (response, _) = self
+			.run(Request::ReloadFonts, None, BASE_TOTAL_TIMEOUT)
+			.await?;
+		let Response::ReloadFonts(response) = response else {
+			bail!("expected ReloadFonts response, got {response:?}");
+		};
+		response.map_err(|error| anyhow!(error))
+	}
+}
+
+/// Waits for space in the channel, if there is one.
+async fn reserve<T>(channel: Option<&mpsc::Sender<T>>) -> Option<mpsc::Permit<'_, T>> {
+	match channel {
+		Some(channel) => channel.reserve().await.ok(),
+		None => std::future::pending().await,
+	}
 }
 
 #[derive(Debug)]
 struct Process {
-	child: Option<Child>,
+	/// Kept apart from the pipes so that the process can be killed while a request is stuck on them.
+	child: Child,
+	/// This is `None` while a request is being communicated,
+	/// and stays `None` if that request was abandoned, such as on a timeout.
+	pipes: Option<(ChildStdin, ChildStdout)>,
 }
 
 impl Process {
 	async fn spawn() -> anyhow::Result<Self> {
-		let child = std::process::Command::new("./worker")
+		let mut child = std::process::Command::new("./worker")
 			.stdin(Stdio::piped())
 			.stdout(Stdio::piped())
 			.stderr(Stdio::inherit())
 			.spawn()
 			.context("spawning worker process.\n\nthis is likely because you are trying to run the bot from a checkout of the repo and `worker` is a directory. you can fix this by changing the path to the worker binary to point to the worker binary in the cargo target directory. alternatively, follow the instructions in the README that describe how to set up a standalone installation.")?;
 
-		let mut ret = Self { child: Some(child) };
+		let pipes = child.stdin.take().zip(child.stdout.take());
+		let mut ret = Self { child, pipes };
 		// Ask for the version and ignore it, as a health check.
 		ret
 			.communicate(Request::Version, None)
@@ -140,11 +412,15 @@ impl Process {
 	async fn replace(&mut self) -> anyhow::Result<()> {
 		let new = Self::spawn().await?;
 		let old = std::mem::replace(self, new);
+		old.kill().await
+	}
+
+	async fn kill(self) -> anyhow::Result<()> {
+		// Killing the process also ends any request stuck on its pipes, along with the thread it's on.
+		let mut child = self.child;
 		tokio::task::spawn_blocking(move || {
-			if let Some(mut child) = old.child {
-				_ = child.kill();
-				_ = child.wait();
-			}
+			_ = child.kill();
+			_ = child.wait();
 		})
 		.await
 		.context("joining kill task")?;
@@ -154,21 +430,24 @@ impl Process {
 	async fn communicate(
 		&mut self,
 		request: Request,
-		progress_channel: Option<mpsc::Sender<String>>,
+		progress_channel: Option<mpsc::Sender<Progress>>,
 	) -> anyhow::Result<Response> {
-		let mut child = self.child.take().unwrap();
-		let (child, res) = tokio::task::spawn_blocking(move || {
+		let mut pipes = self
+			.pipes
+			.take()
+			.context("the worker is still busy with an abandoned request")?;
+		let (pipes, res) = tokio::task::spawn_blocking(move || {
 			fn inner(
-				child: &mut Child,
+				(stdin, stdout): &mut (ChildStdin, ChildStdout),
 				request: &Request,
-				progress_channel: &Option<mpsc::Sender<String>>,
+				progress_channel: Option<&mpsc::Sender<Progress>>,
 			) -> bincode::Result<Response> {
-				bincode::serialize_into(child.stdin.as_mut().unwrap(), &request)?;
+				bincode::serialize_into(stdin, &request)?;
 				loop {
-					let response: Response = bincode::deserialize_from(child.stdout.as_mut().unwrap())?;
+					let response: Response = bincode::deserialize_from(&mut *stdout)?;
 
 					if let Response::Progress(progress) = response {
-						if let Some(chan) = &progress_channel {
+						if let Some(chan) = progress_channel {
 							_ = chan.blocking_send(progress);
 						}
 					} else {
@@ -176,12 +455,12 @@ impl Process {
 					}
 				}
 			}
-			let res = inner(&mut child, &request, &progress_channel);
-			(child, res)
+			let res = inner(&mut pipes, &request, progress_channel.as_ref());
+			(pipes, res)
 		})
 		.await
 		.context("joining communication task")?;
-		self.child = Some(child);
+		self.pipes = Some(pipes);
 		res.context("communicating with worker")
 	}
 }
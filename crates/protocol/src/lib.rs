@@ -1,34 +1,340 @@
 use serde::{Deserialize, Serialize};
 
+pub mod syntax;
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct RenderOptions {
+	/// Also return the first page as a `data:` URI, for clients that want to inline the image.
+	pub data_uri: bool,
+	/// Only render the element with this label, cropping the output to it.
+	pub label: Option<String>,
+	/// Tag the PNGs as being in the sRGB color space.
+	pub srgb: bool,
+	/// Pin the date and provide this as `sys.inputs.seed`, so randomness can be made reproducible.
+	pub seed: Option<i64>,
+	/// Forbid the use of packages, so only the standard library is available.
+	pub safe: bool,
+	/// Also return a downscaled copy of the first page.
+	pub thumbnail: bool,
+	/// Split pages that are too big into tiles rather than failing.
+	pub tiles: bool,
+	/// Identifies a source that is edited over time, such as by editing a message.
+	/// The worker keeps the previous source for the session and applies the changes to it,
+	/// so that only the edited part needs to be reparsed and unchanged work can be reused.
+	pub session: Option<u64>,
+	/// What to produce from the compiled document, all from a single compile.
+	///
+	/// PNG and raw are the page images, so at most one of them can be given, and if neither is,
+	/// no images are rendered. The others are returned in `Rendered::exports`.
+	/// If the list is empty, only PNG images are produced.
+	pub formats: Vec<OutputFormat>,
+	/// Outline the bounds of each frame in the layout, for debugging it.
+	pub boxes: bool,
+	/// Render at most this many pages, if it's fewer than the usual limit.
+	pub max_pages: Option<usize>,
+	/// Downscale each image so that neither its width nor its height is more than this many pixels.
+	pub max_dimension: Option<u32>,
+	/// Fail with the warnings, if there are any, instead of rendering.
+	pub strict: bool,
+	/// Crop or pad each image to this aspect ratio, given as width and height.
+	pub aspect: Option<(u32, u32)>,
+	/// Reduce each image to at most this many colors, chosen to suit the image.
+	pub colors: Option<u16>,
+	/// The largest width or height of a page, in points, if it's less than `LimitsResponse::page_size`.
+	/// Larger values are clamped to that.
+	pub max_size: Option<f32>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum OutputFormat {
+	Png,
+	/// Uncompressed RGBA pixels with straight alpha, row by row, for clients that process the
+	/// images further. The dimensions are given in `Rendered::sizes`.
+	///
+	/// `Rendered::data_uri` and `Rendered::thumbnail` are not produced in this format.
+	Raw,
+	/// An SVG of each page.
+	Svg,
+	/// A PDF of the whole document.
+	Pdf,
+}
+
+/// A file exported in one of the formats of `RenderOptions::formats` other than the page images.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Export {
+	pub format: OutputFormat,
+	/// The page, starting at 1, for formats that are exported page by page.
+	pub page: Option<usize>,
+	pub data: Vec<u8>,
+}
+
+/// How an image in `Rendered::images` was actually encoded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Encoding {
+	Png,
+	/// Used instead of PNG when the PNG would exceed the size limit, if the worker is configured
+	/// to fall back to it.
+	Jpeg {
+		quality: u8,
+	},
+	Raw,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum Request {
-	Render { code: String },
-	Ast { code: String },
+	Render {
+		code: String,
+		options: RenderOptions,
+	},
+	/// Render the template of a package, responding with `Render`.
+	Template {
+		/// The package specification, like `@preview/example:0.1.0`.
+		spec: String,
+		options: RenderOptions,
+	},
+	Ast {
+		code: String,
+		/// Annotate each node with the line and column where it starts.
+		spans: bool,
+	},
+	PdfInfo {
+		code: String,
+	},
+	Debug {
+		code: String,
+	},
+	Outline {
+		code: String,
+	},
+	/// Check a package's `typst.toml`.
+	Manifest {
+		manifest: String,
+	},
+	/// Compare the first pages of two documents.
+	Diff {
+		before: String,
+		after: String,
+	},
+	/// Measure how big the text is when set in a font.
+	Measure {
+		text: String,
+		/// The font family, or Typst's default if `None`.
+		font: Option<String>,
+		/// The font size in points, or Typst's default if `None`.
+		size: Option<f64>,
+	},
 	Version,
+	ReloadFonts,
+	ListPackages,
+	Limits,
+	/// Get the worker's settings, for debugging deployments.
+	Config,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Rendered {
 	pub images: Vec<Vec<u8>>,
+	/// The width and height of each image, in pixels.
+	pub sizes: Vec<(u32, u32)>,
+	/// How each image was encoded.
+	pub encodings: Vec<Encoding>,
 	pub more_pages: usize,
 	pub warnings: String,
+	/// The number of warnings in `warnings`.
+	pub warning_count: usize,
+	/// The first page encoded as a `data:` URI, if requested.
+	pub data_uri: Option<String>,
+	/// A downscaled PNG of the first page, if requested.
+	pub thumbnail: Option<Vec<u8>>,
+	/// The pages that were split into tiles, in order.
+	pub tiled: Vec<TiledPage>,
+	/// The page, starting at 1, that took too long to render, if any.
+	/// It and the pages after it are counted in `more_pages`.
+	pub timed_out_page: Option<usize>,
+	/// The files in the requested formats other than the page images, in the order the formats were requested.
+	pub exports: Vec<Export>,
+}
+
+/// A page that was split into tiles, which are consecutive in `Rendered::images` in row-major order.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct TiledPage {
+	/// The index of the first tile in `Rendered::images`.
+	pub first_image: usize,
+	pub columns: u32,
+	pub rows: u32,
 }
 
 pub type RenderResponse = Result<Rendered, String>;
 
-pub type AstResponse = String;
+pub type AstResponse = Result<String, String>;
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PdfInfo {
+	/// The size of the exported PDF, in bytes.
+	pub bytes: usize,
+	/// The width and height of each page, in points.
+	pub page_sizes: Vec<(f64, f64)>,
+	/// The families of the fonts used in the document, in sorted order.
+	pub fonts: Vec<String>,
+	pub title: Option<String>,
+	pub authors: Vec<String>,
+}
+
+pub type PdfInfoResponse = Result<PdfInfo, String>;
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DebugInfo {
+	/// The syntax tree, formatted like `Response::Ast`.
+	pub ast: String,
+	/// The errors and warnings from compiling, formatted like `Rendered::warnings`.
+	pub diagnostics: String,
+}
+
+pub type DebugResponse = Result<DebugInfo, String>;
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct OutlineEntry {
+	/// The heading's level, starting at 1 for top-level headings.
+	pub level: usize,
+	/// The plain text of the heading's body.
+	pub text: String,
+	/// The page that the heading is on, starting at 1.
+	pub page: usize,
+}
+
+/// The document's headings, in order.
+pub type OutlineResponse = Result<Vec<OutlineEntry>, String>;
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ManifestSummary {
+	pub name: String,
+	pub version: String,
+	pub entrypoint: String,
+	/// The minimum Typst version required by the package, if given.
+	pub compiler: Option<String>,
+	/// Whether the worker's Typst version is at least `compiler`.
+	pub compiler_supported: bool,
+	/// The path of the template's entrypoint, if the package is a template.
+	pub template: Option<String>,
+}
+
+/// The summary of a valid manifest, or the parse error.
+pub type ManifestResponse = Result<ManifestSummary, String>;
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DiffImage {
+	/// A PNG with the unchanged pixels faded and the changed pixels highlighted.
+	pub image: Vec<u8>,
+	/// The number of pixels that differ, including those only covered by one of the pages.
+	pub changed_pixels: usize,
+	/// The sizes of the two pages in pixels, if they differ.
+	pub size_mismatch: Option<((u32, u32), (u32, u32))>,
+}
+
+pub type DiffResponse = Result<DiffImage, String>;
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct TextSize {
+	/// In points.
+	pub width: f64,
+	/// From the cap height of the first line to the baseline of the last, in points.
+	pub height: f64,
+}
+
+pub type MeasureResponse = Result<TextSize, String>;
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct VersionResponse {
 	pub version: String,
 }
 
+/// Package specifications, like `@preview/example:0.1.0`.
+pub type ListPackagesResponse = Vec<String>;
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ReloadedFonts {
+	/// The number of fonts loaded.
+	pub fonts: usize,
+}
+
+/// If the fonts can't be loaded, the error, with the previous fonts still in use.
+pub type ReloadFontsResponse = Result<ReloadedFonts, String>;
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct LimitsResponse {
+	/// The maximum number of pages that will be rendered.
+	pub pages: usize,
+	/// The maximum total size of the rendered images, in bytes.
+	pub bytes: usize,
+	/// The maximum width or height of a page, in points.
+	pub page_size: f32,
+	/// The maximum length of the source, in bytes, if there is one.
+	pub source_length: Option<usize>,
+}
+
+/// The worker's effective settings, from its env vars and their defaults.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ConfigResponse {
+	pub cache_directory: String,
+	/// The number of packages in the cache directory.
+	pub cached_packages: usize,
+	/// The number of fonts loaded, including the bundled ones.
+	pub fonts: usize,
+	pub font_directory: Option<String>,
+	/// Lowercased font families that are left out.
+	pub excluded_fonts: Vec<String>,
+	pub watermark: bool,
+	pub jpeg_fallback: Vec<u8>,
+	pub max_source_length: Option<usize>,
+	/// In seconds.
+	pub package_connect_timeout: u64,
+	/// In seconds.
+	pub package_read_timeout: u64,
+	pub package_user_agent: String,
+}
+
+/// A stage of handling a request.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum Progress {
+	DownloadingPackage {
+		/// The package specification, like `@preview/example:0.1.0`.
+		spec: String,
+	},
+	/// Sent periodically while the package from the last `DownloadingPackage` is downloading.
+	DownloadedBytes {
+		downloaded: u64,
+		/// The size of the package, if the registry gave one.
+		total: Option<u64>,
+	},
+	Compiling,
+	Rendering {
+		/// The page being rendered, starting at 1.
+		page: usize,
+		/// The number of pages that will be rendered.
+		total: usize,
+	},
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub enum Response {
 	Render(RenderResponse),
 	Ast(AstResponse),
+	PdfInfo(PdfInfoResponse),
+	Debug(DebugResponse),
+	Outline(OutlineResponse),
+	Manifest(ManifestResponse),
+	Diff(DiffResponse),
+	Measure(MeasureResponse),
 	Version(VersionResponse),
+	ReloadFonts(ReloadFontsResponse),
+	ListPackages(ListPackagesResponse),
+	Limits(LimitsResponse),
+	Config(ConfigResponse),
+	/// The request couldn't be parsed, with the reason.
+	///
+	/// Only sent with the JSON protocol, after which the worker keeps reading requests.
+	InvalidRequest(String),
 	/// This can be sent at any time and is not considered a final response for a request,
 	/// but can be shown to the user in the meantime as a progress update.
-	Progress(String),
+	Progress(Progress),
 }
@@ -0,0 +1,29 @@
+//! Helpers for writing Typst source, shared by the bot and the worker.
+
+/// Escapes the text into a Typst string literal, including the surrounding quotes.
+pub fn string_literal(text: &str) -> String {
+	let mut literal = String::with_capacity(text.len() + 2);
+	literal.push('"');
+	for ch in text.chars() {
+		match ch {
+			'"' | '\\' => {
+				literal.push('\\');
+				literal.push(ch);
+			}
+			'\n' => literal.push_str("\\n"),
+			'\r' => literal.push_str("\\r"),
+			'\t' => literal.push_str("\\t"),
+			_ => literal.push(ch),
+		}
+	}
+	literal.push('"');
+	literal
+}
+
+#[test]
+fn test_string_literal() {
+	assert_eq!(string_literal("hello"), r#""hello""#);
+	assert_eq!(string_literal(r#"a "b" \c"#), r#""a \"b\" \\c""#);
+	assert_eq!(string_literal("a\nb"), r#""a\nb""#);
+	assert_eq!(string_literal("a\tb\r\n"), r#""a\tb\r\n""#);
+}
@@ -0,0 +1,174 @@
+use tiny_skia::{BlendMode, Color, Pixmap, PixmapPaint, PremultipliedColorU8, Transform};
+
+/// The pixels of an image that differ from its background, as left, top, right, and bottom,
+/// with right and bottom exclusive.
+type ContentBounds = (u32, u32, u32, u32);
+
+/// Where to put an image on a canvas of the target aspect ratio,
+/// as the canvas's width and height and the image's offset within it.
+///
+/// A negative offset crops the image; a positive one pads it.
+type Layout = (u32, u32, i64, i64);
+
+/// The most pixels the canvas may have, the same budget as the image rendered for cropping.
+const MAX_CANVAS_PIXELS: u64 = 25_000_000;
+
+/// Fits the image to the aspect ratio, given as width and height.
+///
+/// If only background would be lost, the image is cropped around its content;
+/// otherwise it's padded with the background, so nothing is ever cut off.
+/// The background is taken to be the color of the top left pixel.
+/// Fails if padding would make the image too large.
+pub fn fit(pixmap: &Pixmap, ratio: (u32, u32)) -> Result<Pixmap, String> {
+	let background = pixmap
+		.pixel(0, 0)
+		.unwrap_or(PremultipliedColorU8::TRANSPARENT);
+	let content = content_bounds(pixmap, background);
+	let (width, height, x, y) = layout((pixmap.width(), pixmap.height()), content, ratio);
+	if (width, height) == (pixmap.width(), pixmap.height()) {
+		return Ok(pixmap.clone());
+	}
+
+	let pixels = u64::from(width) * u64::from(height);
+	if pixels > MAX_CANVAS_PIXELS {
+		return Err(format!(
+			"fitting the image to the aspect ratio would take {pixels} pixels but the maximum is {MAX_CANVAS_PIXELS}"
+		));
+	}
+	let mut canvas =
+		Pixmap::new(width, height).ok_or("the image can't be fit to the aspect ratio")?;
+	let background = background.demultiply();
+	canvas.fill(Color::from_rgba8(
+		background.red(),
+		background.green(),
+		background.blue(),
+		background.alpha(),
+	));
+	let paint = PixmapPaint {
+		// Copy the pixels exactly, rather than blending any translucent ones with the background.
+		blend_mode: BlendMode::Source,
+		..PixmapPaint::default()
+	};
+	// The offsets are within the canvas, which is limited to far fewer than `i32::MAX` pixels.
+	canvas.draw_pixmap(
+		i32::try_from(x).unwrap(),
+		i32::try_from(y).unwrap(),
+		pixmap.as_ref(),
+		&paint,
+		Transform::identity(),
+		None,
+	);
+	Ok(canvas)
+}
+
+fn content_bounds(pixmap: &Pixmap, background: PremultipliedColorU8) -> ContentBounds {
+	let width = pixmap.width();
+	let (mut left, mut top, mut right, mut bottom) = (width, pixmap.height(), 0, 0);
+
+	for (i, pixel) in pixmap.pixels().iter().enumerate() {
+		if *pixel == background {
+			continue;
+		}
+		// The index is less than the number of pixels, which fits in a `u32` since both dimensions do.
+		let i = u32::try_from(i).unwrap();
+		let (x, y) = (i % width, i / width);
+		left = left.min(x);
+		top = top.min(y);
+		right = right.max(x + 1);
+		bottom = bottom.max(y + 1);
+	}
+
+	if left >= right {
+		// The image is blank, so treat all of it as content to keep it centered.
+		return (0, 0, width, pixmap.height());
+	}
+	(left, top, right, bottom)
+}
+
+fn layout(size: (u32, u32), content: ContentBounds, ratio: (u32, u32)) -> Layout {
+	let (width, height) = (u64::from(size.0), u64::from(size.1));
+	let (ratio_width, ratio_height) = (u64::from(ratio.0), u64::from(ratio.1));
+	let (left, top, right, bottom) = content;
+
+	// The offset to crop one dimension down to `target`, centered on the content,
+	// or `None` if that would cut off some of the content.
+	let crop = |length: u64, target: u64, start: u32, end: u32| {
+		let (start, end) = (u64::from(start), u64::from(end));
+		(end - start <= target).then(|| {
+			let center = (start + end) / 2;
+			let crop_start = center.saturating_sub(target / 2).min(length - target);
+			-i64::try_from(crop_start).unwrap()
+		})
+	};
+	// Lengths that don't fit are far beyond `MAX_CANVAS_PIXELS`, so saturating still gets them rejected.
+	let to_u32 = |length: u64| u32::try_from(length).unwrap_or(u32::MAX);
+	let to_i64 = |offset: u64| i64::try_from(offset).unwrap_or(i64::MAX);
+
+	match (width * ratio_height).cmp(&(height * ratio_width)) {
+		std::cmp::Ordering::Equal => (size.0, size.1, 0, 0),
+		// Too wide, so crop the width or pad the height.
+		std::cmp::Ordering::Greater => {
+			let target = (height * ratio_width / ratio_height).max(1);
+			if let Some(x) = crop(width, target, left, right) {
+				(to_u32(target), size.1, x, 0)
+			} else {
+				let new_height = (width * ratio_height).div_ceil(ratio_width);
+				(
+					size.0,
+					to_u32(new_height),
+					0,
+					to_i64((new_height - height) / 2),
+				)
+			}
+		}
+		// Too tall, so crop the height or pad the width.
+		std::cmp::Ordering::Less => {
+			let target = (width * ratio_height / ratio_width).max(1);
+			if let Some(y) = crop(height, target, top, bottom) {
+				(size.0, to_u32(target), 0, y)
+			} else {
+				let new_width = (height * ratio_width).div_ceil(ratio_height);
+				(
+					to_u32(new_width),
+					size.1,
+					to_i64((new_width - width) / 2),
+					0,
+				)
+			}
+		}
+	}
+}
+
+#[test]
+fn test_layout() {
+	// Already the right ratio.
+	assert_eq!(layout((160, 90), (0, 0, 160, 90), (16, 9)), (160, 90, 0, 0));
+	// Too wide with narrow content, so it's cropped around the content.
+	assert_eq!(layout((400, 90), (10, 0, 50, 90), (16, 9)), (160, 90, 0, 0));
+	assert_eq!(
+		layout((400, 90), (200, 0, 240, 90), (16, 9)),
+		(160, 90, -140, 0)
+	);
+	assert_eq!(
+		layout((400, 90), (350, 0, 400, 90), (16, 9)),
+		(160, 90, -240, 0)
+	);
+	// Too wide with wide content, so it's padded.
+	assert_eq!(
+		layout((320, 90), (0, 0, 320, 90), (16, 9)),
+		(320, 180, 0, 45)
+	);
+	// Too tall with tall content, so it's padded.
+	assert_eq!(
+		layout((100, 100), (0, 0, 100, 100), (2, 1)),
+		(200, 100, 50, 0)
+	);
+	// Too tall with short content, so it's cropped.
+	assert_eq!(layout((100, 100), (0, 0, 100, 20), (2, 1)), (100, 50, 0, 0));
+}
+
+#[test]
+fn test_fit_too_big() {
+	let pixmap = Pixmap::new(50_000, 5).unwrap();
+	assert!(fit(&pixmap, (1, 8)).is_err());
+}
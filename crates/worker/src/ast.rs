@@ -0,0 +1,37 @@
+use std::fmt::Write as _;
+
+use typst::syntax::{LinkedNode, Source};
+
+/// Formats the syntax tree with the line and column where each node starts, both 1-based.
+pub fn format_with_spans(code: String) -> String {
+	let source = Source::detached(code);
+	let mut out = String::new();
+	write_node(&source, &LinkedNode::new(source.root()), 0, &mut out);
+	out
+}
+
+fn write_node(source: &Source, node: &LinkedNode<'_>, depth: usize, out: &mut String) {
+	let offset = node.offset();
+	let line = source.byte_to_line(offset).map_or(0, |line| line + 1);
+	let column = source.byte_to_column(offset).map_or(0, |column| column + 1);
+
+	// The unwraps will never fail since `String`'s `Write` implementation is infallible.
+	write!(
+		out,
+		"{:indent$}{:?} @ {line}:{column}",
+		"",
+		node.kind(),
+		indent = depth * 2
+	)
+	.unwrap();
+
+	let mut children = node.children().peekable();
+	if children.peek().is_none() {
+		writeln!(out, " {:?}", node.text()).unwrap();
+	} else {
+		out.push('\n');
+		for child in children {
+			write_node(source, &child, depth + 1, out);
+		}
+	}
+}
@@ -0,0 +1,37 @@
+use typst::layout::{Abs, Frame, FrameItem, GroupItem, Page, Point};
+use typst::syntax::Span;
+use typst::visualize::{Color, FixedStroke, Geometry};
+
+/// The thickness of the outlines in points, thin enough to see inside small boxes such as those of single words.
+const THICKNESS: f64 = 0.5;
+
+/// Returns a copy of the page with the bounds of each frame in its layout outlined, for debugging.
+pub fn outline(page: &Page) -> Page {
+	let mut page = page.clone();
+	let boxes = boxes(&page.frame);
+	page.frame.push_frame(Point::zero(), boxes);
+	page
+}
+
+/// Makes a frame that outlines the frame and each one nested in it, at the same positions.
+fn boxes(frame: &Frame) -> Frame {
+	let mut overlay = Frame::soft(frame.size());
+	let stroke = FixedStroke::from_pair(Color::RED, Abs::pt(THICKNESS));
+	overlay.push(
+		Point::zero(),
+		FrameItem::Shape(
+			Geometry::Rect(frame.size()).stroked(stroke),
+			Span::detached(),
+		),
+	);
+
+	for (pos, item) in frame.items() {
+		if let FrameItem::Group(group) = item {
+			let mut inner = GroupItem::new(boxes(&group.frame));
+			inner.transform = group.transform;
+			overlay.push(*pos, FrameItem::Group(inner));
+		}
+	}
+
+	overlay
+}
@@ -0,0 +1,109 @@
+use std::borrow::Cow;
+
+use typst::diag::{SourceDiagnostic, Tracepoint};
+use typst::syntax::package::PackageSpec;
+use typst::syntax::FileId;
+use typst::World;
+
+use crate::sandbox::WithSource;
+
+/// The message Typst gives when a file imports one that's still being evaluated.
+const CYCLIC_IMPORT: &str = "cyclic import";
+
+/// Rewrites Typst's bare "cyclic import" error to list the files in the cycle,
+/// leaving other diagnostics as they are.
+///
+/// Typst already stops at the import that closes the cycle, and the error's trace records each import
+/// that led to it, so the cycle is the chain of importing files from the one that was imported again.
+pub fn annotate<'a>(
+	world: &WithSource<'_>,
+	diagnostic: &'a SourceDiagnostic,
+) -> Cow<'a, SourceDiagnostic> {
+	if diagnostic.message != CYCLIC_IMPORT {
+		return Cow::Borrowed(diagnostic);
+	}
+
+	let chain = import_chain(diagnostic);
+	let files = imported_file(world, diagnostic, &chain).and_then(|target| cycle(&chain, target));
+	let hint = match files {
+		// Naming the first file again at the end shows that it loops.
+		Some(cycle) => format!("the cycle is {}", describe(cycle.iter().chain(&cycle[..1]))),
+		None => format!("the imports leading to it are {}", describe(&chain)),
+	};
+
+	let mut diagnostic = diagnostic.clone();
+	diagnostic.message = "cyclic import detected".into();
+	diagnostic.hints.push(hint.into());
+	Cow::Owned(diagnostic)
+}
+
+/// The files that were being evaluated when the error happened, from the main file to the one with the
+/// import that closes the cycle.
+fn import_chain(diagnostic: &SourceDiagnostic) -> Vec<FileId> {
+	// The trace is innermost first, and each import's span is in the file that contains it.
+	diagnostic
+		.trace
+		.iter()
+		.rev()
+		.filter(|point| matches!(point.v, Tracepoint::Import))
+		.filter_map(|point| point.span.id())
+		.chain(diagnostic.span.id())
+		.collect()
+}
+
+/// The file that the erroring import tried to import again, if it can be worked out from the import's source.
+fn imported_file(
+	world: &WithSource<'_>,
+	diagnostic: &SourceDiagnostic,
+	chain: &[FileId],
+) -> Option<FileId> {
+	let span = diagnostic.span;
+	let source = world.source(span.id()?).ok()?;
+	let text = &source.text()[source.range(span)?];
+	// Imports of anything but a string literal, like a variable, aren't resolved.
+	let path = text.strip_prefix('"')?.strip_suffix('"')?;
+
+	if path.starts_with('@') {
+		// A package's entrypoint isn't known without its manifest, but it's whichever file of the package
+		// was evaluated first.
+		let spec: PackageSpec = path.parse().ok()?;
+		chain.iter().copied().find(|id| id.package() == Some(&spec))
+	} else {
+		span.resolve_path(path).ok()
+	}
+}
+
+/// The part of the chain from `target` on, if it's in the chain.
+fn cycle(chain: &[FileId], target: FileId) -> Option<&[FileId]> {
+	let start = chain.iter().position(|&id| id == target)?;
+	Some(&chain[start..])
+}
+
+/// Lists the files as an arrow-separated path.
+fn describe<'a>(files: impl IntoIterator<Item = &'a FileId>) -> String {
+	files
+		.into_iter()
+		.map(|&id| name(id))
+		.collect::<Vec<_>>()
+		.join(" -> ")
+}
+
+fn name(id: FileId) -> String {
+	let path = id.vpath().as_rootless_path().display();
+	match id.package() {
+		Some(spec) => format!("{spec}/{path}"),
+		None => path.to_string(),
+	}
+}
+
+#[test]
+fn test_cycle() {
+	use typst::syntax::VirtualPath;
+
+	let [main, a, b] =
+		["main.typ", "a.typ", "b.typ"].map(|path| FileId::new(None, VirtualPath::new(path)));
+	let chain = [main, a, b];
+	assert_eq!(cycle(&chain, a), Some(&[a, b][..]));
+	assert_eq!(cycle(&chain, main), Some(&chain[..]));
+	assert_eq!(describe(&chain), "main.typ -> a.typ -> b.typ");
+}
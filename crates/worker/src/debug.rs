@@ -0,0 +1,23 @@
+use protocol::DebugInfo;
+
+use crate::diagnostic::format_diagnostics;
+use crate::render::check_source_length;
+use crate::sandbox::Sandbox;
+
+pub fn debug(sandbox: &Sandbox, source: String) -> Result<DebugInfo, String> {
+	check_source_length(sandbox, &source)?;
+	let world = sandbox.with_source(source);
+	let ast = format!("{:#?}", world.main_source().root());
+
+	let document = typst::compile(&world);
+	let mut diagnostics = document
+		.output
+		.err()
+		.map_or_else(Vec::new, |errors| errors.to_vec());
+	diagnostics.extend(document.warnings);
+
+	Ok(DebugInfo {
+		ast,
+		diagnostics: format_diagnostics(&world, &diagnostics),
+	})
+}
@@ -8,6 +8,7 @@ use typst::diag::SourceDiagnostic;
 use typst::syntax::FileId;
 use typst::World;
 
+use crate::cycle;
 use crate::sandbox::WithSource;
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -210,6 +211,7 @@ pub fn format_diagnostics(sandbox: &WithSource, diagnostics: &[SourceDiagnostic]
 
 	let mut diagnostics = diagnostics.iter();
 	while let Some(diagnostic) = diagnostics.next() {
+		let diagnostic = cycle::annotate(sandbox, diagnostic);
 		let typst_span = diagnostic.span;
 		let span = typst_span.id().map(|file_id| {
 			let source = sandbox
@@ -0,0 +1,49 @@
+use std::collections::HashSet;
+
+use typst::diag::{eco_format, SourceDiagnostic};
+use typst::layout::{Frame, FrameItem};
+use typst::model::Document;
+
+/// The glyph ID that fonts use for their "missing glyph" box.
+const NOTDEF: u16 = 0;
+
+/// Typst silently renders characters that no loaded font supports as boxes ("tofu"),
+/// so we look for them ourselves and produce a warning for each distinct character.
+pub fn missing_glyph_warnings(document: &Document) -> Vec<SourceDiagnostic> {
+	let mut seen = HashSet::new();
+	let mut warnings = Vec::new();
+	for page in &document.pages {
+		collect(&page.frame, &mut seen, &mut warnings);
+	}
+	warnings
+}
+
+fn collect(frame: &Frame, seen: &mut HashSet<char>, warnings: &mut Vec<SourceDiagnostic>) {
+	for (_, item) in frame.items() {
+		match item {
+			FrameItem::Group(group) => collect(&group.frame, seen, warnings),
+			FrameItem::Text(text) => {
+				for glyph in text.glyphs.iter().filter(|glyph| glyph.id == NOTDEF) {
+					let chars = text.text[glyph.range()]
+						.chars()
+						.filter(|ch| !ch.is_whitespace() && !ch.is_control());
+					for ch in chars {
+						if !seen.insert(ch) {
+							continue;
+						}
+						let code_point = u32::from(ch);
+						let warning = SourceDiagnostic::warning(
+							glyph.span.0,
+							eco_format!(
+								"missing glyph: no available font has the character {ch:?} (U+{code_point:04X}), so it was rendered as a box"
+							),
+						)
+						.with_hint("the bot only has a limited set of fonts, which may not cover every script or emoji");
+						warnings.push(warning);
+					}
+				}
+			}
+			_ => {}
+		}
+	}
+}
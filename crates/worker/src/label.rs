@@ -0,0 +1,127 @@
+use typst::foundations::Label;
+use typst::introspection::{Location, Tag};
+use typst::layout::{Abs, Frame, FrameItem, Point, Size, Transform};
+use typst::model::Document;
+
+/// The space left around the labelled element when cropping to it, in points.
+const MARGIN: f64 = 5.0;
+
+/// A region of a page, in points.
+#[derive(Debug, Clone, Copy)]
+pub struct Bounds {
+	pub min: Point,
+	pub max: Point,
+}
+
+impl Bounds {
+	fn include(bounds: &mut Option<Self>, point: Point) {
+		let bounds = bounds.get_or_insert(Self {
+			min: point,
+			max: point,
+		});
+		bounds.min = bounds.min.min(point);
+		bounds.max = bounds.max.max(point);
+	}
+
+	pub fn size(self) -> Size {
+		(self.max - self.min).to_size()
+	}
+
+	/// Grows the bounds by `MARGIN` on each side, without going outside the page.
+	fn pad(self, page: Size) -> Self {
+		let margin = Abs::pt(MARGIN);
+		Self {
+			min: self
+				.min
+				.map(|coordinate| (coordinate - margin).max(Abs::zero())),
+			max: Point::new(
+				(self.max.x + margin).min(page.x),
+				(self.max.y + margin).min(page.y),
+			),
+		}
+	}
+}
+
+/// The size of an item relative to its position, if it's visible.
+fn item_size(item: &FrameItem) -> Option<(Point, Size)> {
+	match item {
+		// Text is positioned at its baseline.
+		FrameItem::Text(text) => {
+			let metrics = text.font.metrics();
+			let ascender = metrics.ascender.at(text.size);
+			let descender = metrics.descender.at(text.size);
+			Some((
+				Point::with_y(-ascender),
+				Size::new(text.width(), ascender - descender),
+			))
+		}
+		FrameItem::Shape(shape, _) => Some((Point::zero(), shape.geometry.bbox_size())),
+		FrameItem::Image(_, size, _) => Some((Point::zero(), *size)),
+		_ => None,
+	}
+}
+
+/// Collects the bounds of all visible items between the start and end tags of an element.
+struct Walker {
+	location: Location,
+	inside: bool,
+	bounds: Option<Bounds>,
+}
+
+impl Walker {
+	fn walk(&mut self, frame: &Frame, ts: Transform) {
+		for (pos, item) in frame.items() {
+			let ts = ts.pre_concat(Transform::translate(pos.x, pos.y));
+			match item {
+				FrameItem::Group(group) => self.walk(&group.frame, ts.pre_concat(group.transform)),
+				FrameItem::Tag(Tag::Start(elem)) if elem.location() == Some(self.location) => {
+					self.inside = true;
+				}
+				FrameItem::Tag(Tag::End(location, _)) if *location == self.location => {
+					self.inside = false;
+				}
+				_ if self.inside => {
+					let Some((offset, size)) = item_size(item) else {
+						continue;
+					};
+					let corners = [
+						offset,
+						offset + Point::with_x(size.x),
+						offset + Point::with_y(size.y),
+						offset + size.to_point(),
+					];
+					for corner in corners {
+						Bounds::include(&mut self.bounds, corner.transform(ts));
+					}
+				}
+				_ => {}
+			}
+		}
+	}
+}
+
+/// Finds the page index and the region of that page covered by the element with the given label.
+pub fn locate(document: &Document, label: &str) -> Result<(usize, Bounds), String> {
+	let introspector = &document.introspector;
+	let elem = introspector
+		.query_label(Label::new(label))
+		.map_err(|error| error.to_string())?;
+	let location = elem
+		.location()
+		.ok_or_else(|| format!("the element labelled <{label}> has no location"))?;
+
+	let index = introspector.position(location).page.get() - 1;
+	let page = &document.pages[index];
+
+	let mut walker = Walker {
+		location,
+		inside: false,
+		bounds: None,
+	};
+	walker.walk(&page.frame, Transform::identity());
+
+	let bounds = walker
+		.bounds
+		.ok_or_else(|| format!("the element labelled <{label}> has no visible content"))?;
+	Ok((index, bounds.pad(page.frame.size())))
+}
@@ -1,14 +1,61 @@
-use std::io::Write as _;
+use std::backtrace::{Backtrace, BacktraceStatus};
+use std::cell::RefCell;
+use std::fmt::Write as _;
+use std::io::{BufRead as _, Write as _};
 use std::panic::AssertUnwindSafe;
+use std::sync::OnceLock;
 
-use protocol::{Request, Response};
+use protocol::{Progress, Request, Response};
 
-use crate::render::render;
+use crate::debug::debug;
+use crate::manifest::check_manifest;
+use crate::measure::measure;
+use crate::outline::outline;
+use crate::pdf_info::pdf_info;
+use crate::render::{check_source_length, diff, limits, render, render_template};
 use crate::sandbox::Sandbox;
 
+mod aspect;
+mod ast;
+mod boxes;
+mod cycle;
+mod debug;
 mod diagnostic;
+mod glyphs;
+mod label;
+mod manifest;
+mod measure;
+mod outline;
+mod pdf_info;
 mod render;
+mod safe;
 mod sandbox;
+mod watermark;
+
+thread_local! {
+	/// Where the latest panic on this thread happened, and its backtrace if backtraces are enabled,
+	/// recorded by the hook from [`install_panic_hook`] until [`panic_to_string`] takes them.
+	static PANIC_DETAILS: RefCell<Option<(String, Option<Backtrace>)>> = const { RefCell::new(None) };
+}
+
+/// Records the details of panics for [`panic_to_string`], since the payload doesn't include them.
+///
+/// The default hook still runs afterward, so panics are logged to stderr as before.
+/// Nothing is done until a panic happens, so this doesn't slow down renders.
+fn install_panic_hook() {
+	let default_hook = std::panic::take_hook();
+	std::panic::set_hook(Box::new(move |info| {
+		let location = info
+			.location()
+			.map_or_else(|| "an unknown location".to_owned(), ToString::to_string);
+		// Only captured if enabled with `RUST_BACKTRACE` or `RUST_LIB_BACKTRACE`.
+		let backtrace = Backtrace::capture();
+		let backtrace = (backtrace.status() == BacktraceStatus::Captured).then_some(backtrace);
+		// The thread-local is only unavailable while the thread is exiting.
+		_ = PANIC_DETAILS.try_with(|details| *details.borrow_mut() = Some((location, backtrace)));
+		default_hook(info);
+	}));
+}
 
 fn panic_to_string(panic: &dyn std::any::Any) -> String {
 	let inner = panic
@@ -16,52 +63,171 @@ fn panic_to_string(panic: &dyn std::any::Any) -> String {
 		.copied()
 		.or_else(|| panic.downcast_ref::<String>().map(String::as_str))
 		.unwrap_or("Box<dyn Any>");
-	format!("panicked at '{inner}'")
+	let mut message = format!("panicked at '{inner}'");
+	if let Some((location, backtrace)) = PANIC_DETAILS.with(|details| details.borrow_mut().take()) {
+		write!(message, " at {location}").unwrap();
+		if let Some(backtrace) = backtrace {
+			write!(message, "\n\nbacktrace:\n{backtrace}").unwrap();
+		}
+	}
+	message
 }
 
-fn write_response(response: &Response) {
-	let mut stdout = std::io::stdout().lock();
-	bincode::serialize_into(&mut stdout, &response).unwrap();
-	stdout.flush().unwrap();
+/// A panic caught on one thread, along with the details that the hook recorded there,
+/// so that it can be continued on another thread without losing them.
+pub struct Panic {
+	payload: Box<dyn std::any::Any + Send>,
+	details: Option<(String, Option<Backtrace>)>,
+}
+
+impl Panic {
+	/// Runs `f`, catching a panic in it.
+	pub fn catch<T>(f: impl FnOnce() -> T) -> Result<T, Self> {
+		std::panic::catch_unwind(AssertUnwindSafe(f)).map_err(|payload| Self {
+			payload,
+			details: PANIC_DETAILS.with(|details| details.borrow_mut().take()),
+		})
+	}
+
+	/// Continues the panic on the current thread, where [`catch_panic`] reports it with its original details.
+	pub fn resume(self) -> ! {
+		PANIC_DETAILS.with(|details| *details.borrow_mut() = self.details);
+		std::panic::resume_unwind(self.payload)
+	}
 }
 
-/// This can be changed to `&str` by changing the field in the protocol response to a `Cow`,
-/// but currently there's no reason to because the string is dynamically formatted anyway.
-fn write_progress(msg: String) {
-	write_response(&Response::Progress(msg));
+/// Runs `f`, converting a panic into an error message so the worker stays alive.
+fn catch_panic<T>(f: impl FnOnce() -> Result<T, String>) -> Result<T, String> {
+	std::panic::catch_unwind(AssertUnwindSafe(f))
+		.map_err(|panic| panic_to_string(&*panic))
+		.and_then(|inner| inner)
 }
 
-fn main() {
-	let sandbox = Sandbox::new();
+#[test]
+fn test_panic_across_threads() {
+	install_panic_hook();
+	let panic = std::thread::spawn(|| Panic::catch(|| panic!("oops")).unwrap_err())
+		.join()
+		.unwrap();
+	let message = catch_panic::<()>(|| panic.resume()).unwrap_err();
+	assert!(message.starts_with(&format!("panicked at 'oops' at {}:", file!())));
+}
+
+/// Whether requests and responses are JSON lines rather than bincode, for driving the worker from other languages.
+///
+/// Set with `WORKER_PROTOCOL=json`; the bot always uses bincode.
+fn json_protocol() -> bool {
+	static JSON: OnceLock<bool> = OnceLock::new();
+	*JSON.get_or_init(|| match std::env::var("WORKER_PROTOCOL") {
+		Ok(protocol) if protocol.eq_ignore_ascii_case("json") => true,
+		Ok(protocol) if protocol.eq_ignore_ascii_case("bincode") => false,
+		Ok(protocol) => panic!("unknown `WORKER_PROTOCOL` {protocol:?}, expected `json` or `bincode`"),
+		Err(_) => false,
+	})
+}
 
-	loop {
-		let res = bincode::deserialize_from(std::io::stdin().lock());
+/// Reads the next request, or `None` at the end of input.
+fn read_request() -> Option<Request> {
+	let mut stdin = std::io::stdin().lock();
 
-		if let Err(error) = &res {
-			if let bincode::ErrorKind::Io(error) = &**error {
-				if error.kind() == std::io::ErrorKind::UnexpectedEof {
-					break;
+	if json_protocol() {
+		// Read bytes rather than a `String` so invalid UTF-8 is reported like any other malformed line.
+		let mut line = Vec::new();
+		loop {
+			line.clear();
+			match stdin.read_until(b'\n', &mut line) {
+				Ok(0) => return None,
+				Ok(_) => {}
+				Err(error) => {
+					write_response(&Response::InvalidRequest(error.to_string()));
+					return None;
 				}
 			}
+			if line.trim_ascii().is_empty() {
+				continue;
+			}
+			match serde_json::from_slice(&line) {
+				Ok(request) => return Some(request),
+				// Other programs may well send something malformed, which shouldn't take the worker down.
+				Err(error) => write_response(&Response::InvalidRequest(error.to_string())),
+			}
 		}
+	}
+
+	let res = bincode::deserialize_from(stdin);
+
+	if let Err(error) = &res {
+		if let bincode::ErrorKind::Io(error) = &**error {
+			if error.kind() == std::io::ErrorKind::UnexpectedEof {
+				return None;
+			}
+		}
+	}
 
-		let request: Request = res.unwrap();
+	Some(res.unwrap())
+}
 
+fn write_response(response: &Response) {
+	let mut stdout = std::io::stdout().lock();
+	if json_protocol() {
+		serde_json::to_writer(&mut stdout, &response).unwrap();
+		stdout.write_all(b"\n").unwrap();
+	} else {
+		bincode::serialize_into(&mut stdout, &response).unwrap();
+	}
+	stdout.flush().unwrap();
+}
+
+fn write_progress(progress: Progress) {
+	write_response(&Response::Progress(progress));
+}
+
+fn main() {
+	install_panic_hook();
+
+	let mut sandbox = Sandbox::new();
+	let watermark = watermark::load(&sandbox);
+
+	while let Some(request) = read_request() {
 		let response = match request {
-			Request::Render { code } => {
-				let response = std::panic::catch_unwind(AssertUnwindSafe(|| render(&sandbox, code)));
-				let response = response
-					.map_err(|panic| panic_to_string(&*panic))
-					.and_then(|inner| inner);
-				Response::Render(response)
+			Request::Render { code, options } => Response::Render(catch_panic(|| {
+				render(&sandbox, watermark.as_ref(), code, &options)
+			})),
+			Request::Template { spec, options } => Response::Render(catch_panic(|| {
+				render_template(&sandbox, watermark.as_ref(), &spec, &options)
+			})),
+			Request::PdfInfo { code } => Response::PdfInfo(catch_panic(|| pdf_info(&sandbox, code))),
+			Request::Debug { code } => Response::Debug(catch_panic(|| debug(&sandbox, code))),
+			Request::Outline { code } => Response::Outline(catch_panic(|| outline(&sandbox, code))),
+			Request::Manifest { manifest } => {
+				Response::Manifest(catch_panic(|| check_manifest(&manifest)))
+			}
+			Request::Diff { before, after } => {
+				Response::Diff(catch_panic(|| diff(&sandbox, before, after)))
 			}
-			Request::Ast { code } => {
-				let ast = typst::syntax::parse(&code);
-				Response::Ast(format!("{ast:#?}"))
+			Request::Measure { text, font, size } => Response::Measure(catch_panic(|| {
+				measure(&sandbox, &text, font.as_deref(), size)
+			})),
+			Request::Ast { code, spans } => {
+				Response::Ast(check_source_length(&sandbox, &code).map(|()| {
+					if spans {
+						ast::format_with_spans(code)
+					} else {
+						let ast = typst::syntax::parse(&code);
+						format!("{ast:#?}")
+					}
+				}))
 			}
 			Request::Version => Response::Version(protocol::VersionResponse {
 				version: env!("TYPST_VERSION").into(),
 			}),
+			Request::ReloadFonts => Response::ReloadFonts(catch_panic(|| {
+				let fonts = sandbox.reload_fonts()?;
+				Ok(protocol::ReloadedFonts { fonts })
+			})),
+			Request::ListPackages => Response::ListPackages(sandbox.cached_packages()),
+			Request::Limits => Response::Limits(limits(&sandbox)),
+			Request::Config => Response::Config(sandbox.config(watermark.is_some())),
 		};
 
 		comemo::evict(100);
@@ -0,0 +1,24 @@
+use protocol::ManifestSummary;
+use typst::syntax::package::{PackageManifest, PackageVersion};
+
+/// Parses a `typst.toml` the same way Typst does when loading a package.
+pub fn check_manifest(manifest: &str) -> Result<ManifestSummary, String> {
+	let manifest: PackageManifest = toml::from_str(manifest).map_err(|error| error.to_string())?;
+	let package = manifest.package;
+
+	let current: PackageVersion = env!("TYPST_VERSION").parse().unwrap();
+	let compiler_supported = package
+		.compiler
+		.is_none_or(|compiler| current.matches_ge(&compiler));
+
+	Ok(ManifestSummary {
+		name: package.name.into(),
+		version: package.version.to_string(),
+		entrypoint: package.entrypoint.into(),
+		compiler: package.compiler.map(|compiler| compiler.to_string()),
+		compiler_supported,
+		template: manifest
+			.template
+			.map(|template| format!("{}/{}", template.path, template.entrypoint)),
+	})
+}
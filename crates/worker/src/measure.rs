@@ -0,0 +1,52 @@
+use std::fmt::Write as _;
+
+use protocol::syntax::string_literal;
+use protocol::TextSize;
+use typst::World as _;
+
+use crate::diagnostic::format_diagnostics;
+use crate::render::check_source_length;
+use crate::sandbox::Sandbox;
+
+/// Measures the text by compiling a document with a page that fits it exactly.
+pub fn measure(
+	sandbox: &Sandbox,
+	text: &str,
+	font: Option<&str>,
+	size: Option<f64>,
+) -> Result<TextSize, String> {
+	check_source_length(sandbox, text)?;
+
+	let mut source = String::from("#set page(width: auto, height: auto, margin: 0pt)\n");
+	if let Some(font) = font {
+		writeln!(source, "#set text(font: {})", string_literal(font)).unwrap();
+	}
+	if let Some(size) = size {
+		writeln!(source, "#set text(size: {size}pt)").unwrap();
+	}
+	// The text is a string rather than markup, so it's measured exactly as written.
+	writeln!(source, "#box({})", string_literal(text)).unwrap();
+
+	let world = sandbox.with_source(source);
+	if let Some(font) = font {
+		// Typst would silently fall back to another font, which would give misleading measurements.
+		if !world.book().contains_family(&font.to_lowercase()) {
+			return Err(format!("there is no font named {font:?}"));
+		}
+	}
+
+	let document = typst::compile(&world)
+		.output
+		.map_err(|diags| format_diagnostics(&world, &diags))?;
+	let size = document
+		.pages
+		.first()
+		.ok_or("the text produced no pages")?
+		.frame
+		.size();
+
+	Ok(TextSize {
+		width: size.x.to_pt(),
+		height: size.y.to_pt(),
+	})
+}
@@ -0,0 +1,35 @@
+use protocol::OutlineEntry;
+use typst::foundations::{NativeElement as _, StyleChain};
+use typst::model::HeadingElem;
+
+use crate::diagnostic::format_diagnostics;
+use crate::render::check_source_length;
+use crate::sandbox::Sandbox;
+
+pub fn outline(sandbox: &Sandbox, source: String) -> Result<Vec<OutlineEntry>, String> {
+	check_source_length(sandbox, &source)?;
+	let world = sandbox.with_source(source);
+
+	let document = typst::compile(&world)
+		.output
+		.map_err(|diags| format_diagnostics(&world, &diags))?;
+
+	let introspector = &document.introspector;
+	let entries = introspector
+		.query(&HeadingElem::elem().select())
+		.iter()
+		.filter_map(|content| {
+			let heading = content.to_packed::<HeadingElem>()?;
+			let page = content
+				.location()
+				.map_or(0, |location| introspector.page(location).get());
+			Some(OutlineEntry {
+				level: heading.resolve_level(StyleChain::default()).get(),
+				text: heading.body().plain_text().into(),
+				page,
+			})
+		})
+		.collect();
+
+	Ok(entries)
+}
@@ -0,0 +1,59 @@
+use std::collections::BTreeSet;
+
+use protocol::PdfInfo;
+use typst::layout::{Frame, FrameItem};
+use typst_pdf::PdfOptions;
+
+use crate::diagnostic::format_diagnostics;
+use crate::render::check_source_length;
+use crate::sandbox::Sandbox;
+
+fn collect_fonts(frame: &Frame, fonts: &mut BTreeSet<String>) {
+	for (_, item) in frame.items() {
+		match item {
+			FrameItem::Group(group) => collect_fonts(&group.frame, fonts),
+			FrameItem::Text(text) => {
+				fonts.insert(text.font.info().family.clone());
+			}
+			_ => {}
+		}
+	}
+}
+
+pub fn pdf_info(sandbox: &Sandbox, source: String) -> Result<PdfInfo, String> {
+	check_source_length(sandbox, &source)?;
+	let world = sandbox.with_source(source);
+
+	let document = typst::compile(&world)
+		.output
+		.map_err(|diags| format_diagnostics(&world, &diags))?;
+	let pdf = typst_pdf::pdf(&document, &PdfOptions::default())
+		.map_err(|diags| format_diagnostics(&world, &diags))?;
+
+	let page_sizes = document
+		.pages
+		.iter()
+		.map(|page| {
+			let size = page.frame.size();
+			(size.x.to_pt(), size.y.to_pt())
+		})
+		.collect();
+
+	let mut fonts = BTreeSet::new();
+	for page in &document.pages {
+		collect_fonts(&page.frame, &mut fonts);
+	}
+
+	Ok(PdfInfo {
+		bytes: pdf.len(),
+		page_sizes,
+		fonts: fonts.into_iter().collect(),
+		title: document.info.title.as_ref().map(ToString::to_string),
+		authors: document
+			.info
+			.author
+			.iter()
+			.map(ToString::to_string)
+			.collect(),
+	})
+}
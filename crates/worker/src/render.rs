@@ -1,40 +1,65 @@
 use std::io::Cursor;
+use std::sync::mpsc::RecvTimeoutError;
+use std::sync::Mutex;
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
 
-use protocol::Rendered;
-use typst::layout::{Axis, Size};
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine as _;
+use color_quant::NeuQuant;
+use image::imageops::FilterType;
+use protocol::{
+	DiffImage, Encoding, Export, LimitsResponse, OutputFormat, Progress, RenderOptions, Rendered,
+	TiledPage,
+};
+use tiny_skia::{IntRect, IntSize, Pixmap, PremultipliedColorU8};
+use typst::diag::SourceResult;
+use typst::layout::{Axis, Frame, Page, Point, Size};
+use typst::model::Document;
+use typst_pdf::PdfOptions;
 
 use crate::diagnostic::format_diagnostics;
-use crate::sandbox::Sandbox;
+use crate::glyphs::missing_glyph_warnings;
+use crate::label::{self, Bounds};
+use crate::safe;
+use crate::sandbox::{Sandbox, WithSource};
+use crate::watermark;
+use crate::{aspect, boxes, Panic};
 
 const DESIRED_RESOLUTION: f32 = 1000.0;
 const MAX_SIZE: f32 = 10000.0;
 const MAX_PIXELS_PER_POINT: f32 = 5.0;
+/// When cropping to a labelled element, the whole page is rendered at the element's resolution,
+/// so this limits the size of that intermediate image.
+const MAX_CROP_SOURCE_PIXELS: f32 = 25_000_000.0;
 
 #[derive(Debug, thiserror::Error)]
-#[error(
-	"rendered output was too big: the {axis:?} axis was {size} pt but the maximum is {MAX_SIZE}"
-)]
+#[error("rendered output was too big: the {axis:?} axis was {size} pt but the maximum is {max}")]
 pub struct TooBig {
 	size: f32,
 	axis: Axis,
+	max: f32,
 }
 
-fn determine_pixels_per_point(size: Size) -> Result<f32, TooBig> {
+/// Determines the resolution to render at, failing if either side is longer than `max_size` points.
+fn determine_pixels_per_point(size: Size, max_size: f32) -> Result<f32, TooBig> {
 	// We want to truncate.
 	#![allow(clippy::cast_possible_truncation)]
 
 	let x = size.x.to_pt() as f32;
 	let y = size.y.to_pt() as f32;
 
-	if x > MAX_SIZE {
+	if x > max_size {
 		Err(TooBig {
 			size: x,
 			axis: Axis::X,
+			max: max_size,
 		})
-	} else if y > MAX_SIZE {
+	} else if y > max_size {
 		Err(TooBig {
 			size: y,
 			axis: Axis::Y,
+			max: max_size,
 		})
 	} else {
 		let area = x * y;
@@ -43,62 +68,801 @@ fn determine_pixels_per_point(size: Size) -> Result<f32, TooBig> {
 	}
 }
 
+/// Whether every pixel of the RGBA data has the same color, meaning that only the page background was drawn.
+fn is_blank(data: &[u8]) -> bool {
+	let mut pixels = data.chunks_exact(4);
+	let Some(first) = pixels.next() else {
+		return true;
+	};
+	pixels.all(|pixel| pixel == first)
+}
+
+#[test]
+fn test_is_blank() {
+	assert!(is_blank(&[]));
+	assert!(is_blank(&[1, 2, 3, 4]));
+	assert!(is_blank(&[1, 2, 3, 4, 1, 2, 3, 4]));
+	assert!(!is_blank(&[1, 2, 3, 4, 1, 2, 3, 5]));
+}
+
+/// Like `determine_pixels_per_point`, but for a page that will be cropped down to `bounds`.
+fn determine_crop_pixels_per_point(
+	page: Size,
+	bounds: Bounds,
+	max_size: f32,
+) -> Result<f32, TooBig> {
+	// We want to truncate.
+	#![allow(clippy::cast_possible_truncation)]
+
+	determine_pixels_per_point(page, max_size)?;
+	let nominal = determine_pixels_per_point(bounds.size(), max_size)?;
+	let page_area = (page.x.to_pt() * page.y.to_pt()) as f32;
+	Ok(nominal.min((MAX_CROP_SOURCE_PIXELS / page_area).sqrt()))
+}
+
+fn crop(pixmap: &Pixmap, bounds: Bounds, pixels_per_point: f32) -> Option<Pixmap> {
+	// We want to truncate.
+	#![allow(clippy::cast_possible_truncation)]
+
+	let to_pixels = |abs: typst::layout::Abs| (abs.to_pt() as f32 * pixels_per_point) as i32;
+	let rect = IntRect::from_ltrb(
+		to_pixels(bounds.min.x),
+		to_pixels(bounds.min.y),
+		to_pixels(bounds.max.x),
+		to_pixels(bounds.max.y),
+	)?;
+	pixmap.clone_rect(rect)
+}
+
+/// The most tiles that a page can be split into.
+const MAX_TILES: u32 = 8;
+
+/// Determines how many columns and rows of tiles are needed for each tile to be within `max_size`
+/// points, or `None` if that would take too many tiles.
+fn tile_grid(size: Size, max_size: f32) -> Option<(u32, u32)> {
+	// The sizes are positive and bounded by the tile limit after the check.
+	#![allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+
+	let columns = (size.x.to_pt() / f64::from(max_size)).ceil() as u32;
+	let rows = (size.y.to_pt() / f64::from(max_size)).ceil() as u32;
+	(columns.saturating_mul(rows) <= MAX_TILES).then_some((columns, rows))
+}
+
+#[test]
+fn test_tile_grid() {
+	use typst::layout::Abs;
+
+	let size = |x, y| Size::new(Abs::pt(x), Abs::pt(y));
+	assert_eq!(tile_grid(size(100.0, 100.0), MAX_SIZE), Some((1, 1)));
+	assert_eq!(tile_grid(size(15_000.0, 100.0), MAX_SIZE), Some((2, 1)));
+	assert_eq!(tile_grid(size(25_000.0, 15_000.0), MAX_SIZE), Some((3, 2)));
+	assert_eq!(tile_grid(size(90_000.0, 100.0), MAX_SIZE), None);
+	assert_eq!(tile_grid(size(1_500.0, 100.0), 1_000.0), Some((2, 1)));
+}
+
+/// Renders the page as a grid of equally sized tiles, in row-major order.
+/// Like [`render_page`], returns `Ok(None)` if the tiles aren't all done by the deadline.
+fn render_tiles(
+	page: &Page,
+	columns: u32,
+	rows: u32,
+	max_size: f32,
+	deadline: Instant,
+) -> Result<Option<Vec<Pixmap>>, TooBig> {
+	let size = page.frame.size();
+	let tile_size = Size::new(size.x / f64::from(columns), size.y / f64::from(rows));
+	let pixels_per_point = determine_pixels_per_point(tile_size, max_size)?;
+
+	let tiles = (0..rows)
+		.flat_map(|row| (0..columns).map(move |column| (row, column)))
+		.map(|(row, column)| {
+			// Shift the page so the tile's area is at the origin; the rest is clipped.
+			let offset = Point::new(
+				-tile_size.x * f64::from(column),
+				-tile_size.y * f64::from(row),
+			);
+			let mut frame = Frame::soft(tile_size);
+			frame.push_frame(offset, page.frame.clone());
+			let mut tile = page.clone();
+			tile.frame = frame;
+			render_page(&tile, pixels_per_point, deadline)
+		})
+		.collect();
+	Ok(tiles)
+}
+
+/// How long a page may take to rasterize before it's abandoned, leaving the pages before it.
+///
+/// This is less than the bot's timeout for progress, which is sent for each page,
+/// so the pages that did render are returned before the bot gives up on the worker.
+const PAGE_TIMEOUT: Duration = Duration::from_secs(4);
+
+/// The most abandoned pages that may keep rendering in the background at once.
+const MAX_ABANDONED_PAGES: usize = 2;
+
+/// The threads of pages that took too long, which are still rendering or haven't been joined.
+static ABANDONED_PAGES: Mutex<Vec<JoinHandle<()>>> = Mutex::new(Vec::new());
+
+/// How long to wait for abandoned pages to finish before giving up on a request.
+///
+/// This is well within the bot's total timeout, leaving time for the request itself.
+const ABANDONED_PAGES_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Rasterizes the page on another thread, returning `None` if it isn't done by the deadline.
+///
+/// Rasterization can't be interrupted, so an abandoned page keeps rendering in the background
+/// until it's done, but the worker can respond in the meantime.
+/// Call [`wait_for_abandoned_pages`] first so that they can't pile up across requests.
+/// A panic while rendering is continued on this thread.
+fn render_page(page: &Page, pixels_per_point: f32, deadline: Instant) -> Option<Pixmap> {
+	let (send, recv) = std::sync::mpsc::channel();
+	let page = page.clone();
+	let handle = std::thread::spawn(move || {
+		_ = send.send(Panic::catch(|| {
+			typst_render::render(&page, pixels_per_point)
+		}));
+	});
+
+	match recv.recv_timeout(deadline.saturating_duration_since(Instant::now())) {
+		Ok(res) => {
+			// The thread is done once it has sent the result.
+			_ = handle.join();
+			Some(res.unwrap_or_else(|panic| panic.resume()))
+		}
+		Err(RecvTimeoutError::Timeout) => {
+			ABANDONED_PAGES.lock().unwrap().push(handle);
+			None
+		}
+		// Panics are caught and sent, so the thread can only have ended without sending if that panicked.
+		Err(RecvTimeoutError::Disconnected) => match handle.join() {
+			Ok(()) => unreachable!("the page's thread ended without sending a result"),
+			Err(payload) => std::panic::resume_unwind(payload),
+		},
+	}
+}
+
+/// Joins the abandoned pages that are done, and waits while there are [`MAX_ABANDONED_PAGES`] still rendering.
+///
+/// The progress is sent again every second in the meantime so that the bot doesn't give up on the worker.
+/// Fails if they're still rendering after [`ABANDONED_PAGES_TIMEOUT`].
+fn wait_for_abandoned_pages(progress: &Progress) -> Result<(), String> {
+	let start = Instant::now();
+	loop {
+		{
+			let mut abandoned = ABANDONED_PAGES.lock().unwrap();
+			let (done, running) = std::mem::take(&mut *abandoned)
+				.into_iter()
+				.partition::<Vec<_>, _>(JoinHandle::is_finished);
+			*abandoned = running;
+			for handle in done {
+				_ = handle.join();
+			}
+			if abandoned.len() < MAX_ABANDONED_PAGES {
+				return Ok(());
+			}
+		}
+		if start.elapsed() >= ABANDONED_PAGES_TIMEOUT {
+			return Err(
+				"the worker is busy finishing pages from earlier renders that took too long; try again later"
+					.into(),
+			);
+		}
+		std::thread::sleep(Duration::from_secs(1));
+		crate::write_progress(progress.clone());
+	}
+}
+
 fn to_string(v: impl ToString) -> String {
 	v.to_string()
 }
 
 const PAGE_LIMIT: usize = 5;
+/// Tiling can produce several images per page, so this keeps the total within Discord's limit of 10
+/// attachments, leaving room for the thumbnail and warnings.
+const IMAGE_LIMIT: usize = MAX_TILES as usize;
 const BYTES_LIMIT: usize = 25 * 1024 * 1024;
 
-pub fn render(sandbox: &Sandbox, source: String) -> Result<Rendered, String> {
-	let world = sandbox.with_source(source);
+pub fn limits(sandbox: &Sandbox) -> LimitsResponse {
+	LimitsResponse {
+		pages: PAGE_LIMIT,
+		bytes: BYTES_LIMIT,
+		page_size: MAX_SIZE,
+		source_length: sandbox.max_source_length(),
+	}
+}
+
+/// Rejects source that's longer than the configured maximum, before any work is done on it.
+///
+/// The bot may check this too, but the worker is where the code is compiled, so it enforces it regardless.
+pub fn check_source_length(sandbox: &Sandbox, source: &str) -> Result<(), String> {
+	match sandbox.max_source_length() {
+		Some(max) if source.len() > max => Err(format!(
+			"the source is {} bytes long but the maximum is {max}",
+			source.len(),
+		)),
+		_ => Ok(()),
+	}
+}
+
+const BLANK_MESSAGE: &str =
+	"the document rendered to a blank page; did you forget to output content?";
+
+/// The size of an image of the given size, scaled down to fit within `max` pixels in both dimensions.
+fn downscaled_size(width: u32, height: u32, max: u32) -> (u32, u32) {
+	let largest = width.max(height);
+	if largest <= max {
+		return (width, height);
+	}
+	let scale = |side: u32| {
+		let scaled = u64::from(side) * u64::from(max) / u64::from(largest);
+		u32::try_from(scaled).unwrap().max(1)
+	};
+	(scale(width), scale(height))
+}
+
+#[test]
+fn test_downscaled_size() {
+	assert_eq!(downscaled_size(100, 50, 200), (100, 50));
+	assert_eq!(downscaled_size(2400, 1200, 1200), (1200, 600));
+	assert_eq!(downscaled_size(1200, 2400, 600), (300, 600));
+	assert_eq!(downscaled_size(5000, 1, 100), (100, 1));
+}
+
+/// Scales the pixmap down to fit within `max` pixels in both dimensions, if it doesn't already.
+fn downscale(pixmap: Pixmap, max: u32) -> Pixmap {
+	let (width, height) = downscaled_size(pixmap.width(), pixmap.height(), max);
+	if (width, height) == (pixmap.width(), pixmap.height()) {
+		return pixmap;
+	}
+
+	// The unwraps will never fail since the buffer's size matches the pixmap's.
+	let image = image::RgbaImage::from_raw(pixmap.width(), pixmap.height(), pixmap.take()).unwrap();
+	// Unlike the sharper filters, a triangle filter never overshoots,
+	// so the premultiplied colors stay no greater than their alpha.
+	let resized = image::imageops::resize(&image, width, height, FilterType::Triangle);
+	Pixmap::from_vec(resized.into_raw(), IntSize::from_wh(width, height).unwrap()).unwrap()
+}
+
+/// How many of the pixels are sampled to choose the colors for `colors`, as one in this many.
+/// This is the usual setting for NeuQuant, trading a little quality for speed.
+const QUANTIZE_SAMPLE_FACTOR: i32 = 10;
+
+/// Reduces the pixmap to at most `colors` colors, chosen to suit the image.
+fn quantize(pixmap: &mut Pixmap, colors: u16) {
+	// The colors are premultiplied, but each palette color is a weighted average of pixels,
+	// so it's still a valid premultiplied color.
+	let quantizer = NeuQuant::new(QUANTIZE_SAMPLE_FACTOR, usize::from(colors), pixmap.data());
+	for pixel in pixmap.data_mut().chunks_exact_mut(4) {
+		quantizer.map_pixel(pixel);
+	}
+}
+
+/// Encodes the pixmap as a PNG, optionally tagging it as being in the sRGB color space.
+fn encode_png(pixmap: &Pixmap, srgb: bool) -> Vec<u8> {
+	let mut writer = Cursor::new(Vec::new());
 
+	// The unwraps will never fail since `Vec`'s `Write` implementation is infallible.
+	if srgb {
+		let mut encoder = png::Encoder::new(&mut writer, pixmap.width(), pixmap.height());
+		encoder.set_color(png::ColorType::Rgba);
+		encoder.set_depth(png::BitDepth::Eight);
+		encoder.set_source_srgb(png::SrgbRenderingIntent::Perceptual);
+		encoder
+			.write_header()
+			.unwrap()
+			.write_image_data(pixmap.data())
+			.unwrap();
+	} else {
+		image::write_buffer_with_format(
+			&mut writer,
+			bytemuck::cast_slice(pixmap.pixels()),
+			pixmap.width(),
+			pixmap.height(),
+			image::ColorType::Rgba8,
+			image::ImageFormat::Png,
+		)
+		.unwrap();
+	}
+
+	writer.into_inner()
+}
+
+/// Returns the pixels as RGBA with straight alpha, since tiny-skia premultiplies them.
+fn raw_pixels(pixmap: &Pixmap) -> Vec<u8> {
+	pixmap
+		.pixels()
+		.iter()
+		.flat_map(|pixel| {
+			let pixel = pixel.demultiply();
+			[pixel.red(), pixel.green(), pixel.blue(), pixel.alpha()]
+		})
+		.collect()
+}
+
+/// Encodes the pixmap as a JPEG, on a white background since JPEG has no transparency.
+fn encode_jpeg(pixmap: &Pixmap, quality: u8) -> Vec<u8> {
+	// The colors are premultiplied, so adding the missing alpha composites them onto white.
+	let rgb: Vec<u8> = pixmap
+		.pixels()
+		.iter()
+		.flat_map(|pixel| {
+			let background = 255 - pixel.alpha();
+			[pixel.red(), pixel.green(), pixel.blue()].map(|channel| channel + background)
+		})
+		.collect();
+
+	let mut writer = Cursor::new(Vec::new());
+	// The unwrap will never fail since `Vec`'s `Write` implementation is infallible.
+	image::codecs::jpeg::JpegEncoder::new_with_quality(&mut writer, quality)
+		.encode(
+			&rgb,
+			pixmap.width(),
+			pixmap.height(),
+			image::ExtendedColorType::Rgb8,
+		)
+		.unwrap();
+	writer.into_inner()
+}
+
+fn encode(pixmap: &Pixmap, encoding: Encoding, srgb: bool) -> Vec<u8> {
+	match encoding {
+		Encoding::Png => encode_png(pixmap, srgb),
+		Encoding::Jpeg { quality } => encode_jpeg(pixmap, quality),
+		Encoding::Raw => raw_pixels(pixmap),
+	}
+}
+
+/// How to encode the page images for the requested formats, or `None` if no images were requested.
+fn image_encoding(formats: &[OutputFormat]) -> Result<Option<Encoding>, String> {
+	if formats.is_empty() {
+		return Ok(Some(Encoding::Png));
+	}
+	let png = formats.contains(&OutputFormat::Png);
+	let raw = formats.contains(&OutputFormat::Raw);
+	match (png, raw) {
+		(true, true) => Err("only one of the PNG and raw formats can be requested".into()),
+		(true, false) => Ok(Some(Encoding::Png)),
+		(false, true) => Ok(Some(Encoding::Raw)),
+		(false, false) => Ok(None),
+	}
+}
+
+/// Exports the document in the requested formats other than the page images,
+/// with the formats that are exported page by page limited to `pages`.
+fn export(
+	document: &Document,
+	pages: &[Page],
+	formats: &[OutputFormat],
+) -> SourceResult<Vec<Export>> {
+	let mut exports = Vec::new();
+	for &format in formats {
+		match format {
+			OutputFormat::Png | OutputFormat::Raw => {}
+			OutputFormat::Svg => {
+				exports.extend(pages.iter().enumerate().map(|(i, page)| Export {
+					format,
+					page: Some(i + 1),
+					data: typst_svg::svg(page).into_bytes(),
+				}));
+			}
+			OutputFormat::Pdf => exports.push(Export {
+				format,
+				page: None,
+				data: typst_pdf::pdf(document, &PdfOptions::default())?,
+			}),
+		}
+	}
+	Ok(exports)
+}
+
+/// The largest width or height of a thumbnail, in pixels.
+const THUMBNAIL_SIZE: u32 = 256;
+
+/// Downscales the PNG or JPEG to fit within [`THUMBNAIL_SIZE`], keeping its aspect ratio.
+fn make_thumbnail(image: &[u8]) -> Vec<u8> {
+	// The unwraps will never fail since the image was encoded by us and `Vec`'s `Write` implementation is infallible.
+	let image = image::load_from_memory(image).unwrap();
+	let mut writer = Cursor::new(Vec::new());
+	image
+		.thumbnail(THUMBNAIL_SIZE, THUMBNAIL_SIZE)
+		.write_to(&mut writer, image::ImageFormat::Png)
+		.unwrap();
+	writer.into_inner()
+}
+
+fn to_data_uri(image: &[u8], encoding: Encoding) -> String {
+	let mime = match encoding {
+		Encoding::Jpeg { .. } => "image/jpeg",
+		Encoding::Png | Encoding::Raw => "image/png",
+	};
+	format!("data:{mime};base64,{}", BASE64.encode(image))
+}
+
+pub fn render(
+	sandbox: &Sandbox,
+	watermark: Option<&Pixmap>,
+	source: String,
+	options: &RenderOptions,
+) -> Result<Rendered, String> {
+	check_source_length(sandbox, &source)?;
+	let world = match options.session {
+		Some(session) => sandbox.with_session_source(session, source),
+		None => sandbox.with_source(source),
+	};
+	render_world(sandbox, watermark, world, options)
+}
+
+/// Renders the default instantiation of a template package.
+pub fn render_template(
+	sandbox: &Sandbox,
+	watermark: Option<&Pixmap>,
+	spec: &str,
+	options: &RenderOptions,
+) -> Result<Rendered, String> {
+	let world = sandbox.with_template(spec)?;
+	render_world(sandbox, watermark, world, options)
+}
+
+fn render_world(
+	sandbox: &Sandbox,
+	watermark: Option<&Pixmap>,
+	mut world: WithSource<'_>,
+	options: &RenderOptions,
+) -> Result<Rendered, String> {
+	if let Some(seed) = options.seed {
+		world = world.seeded(seed);
+	}
+	if options.safe {
+		let errors = safe::package_imports(world.main_source());
+		if !errors.is_empty() {
+			return Err(format_diagnostics(&world, &errors));
+		}
+		world = world.without_packages();
+	}
+
+	let image_encoding = image_encoding(&options.formats)?;
+
+	crate::write_progress(Progress::Compiling);
 	let document = typst::compile(&world);
-	let warnings = document.warnings;
+	let mut warnings = document.warnings;
 	let document = document
 		.output
 		.map_err(|diags| format_diagnostics(&world, &diags))?;
+	warnings.extend(missing_glyph_warnings(&document));
+	if options.strict && !warnings.is_empty() {
+		return Err(format!(
+			"strict mode treats warnings as errors:\n{}",
+			format_diagnostics(&world, &warnings),
+		));
+	}
 
-	let mut total_attachment_size = 0;
+	let selection = options
+		.label
+		.as_deref()
+		.map(|label| label::locate(&document, label))
+		.transpose()?;
+	let pages = match selection {
+		Some((index, _)) => &document.pages[index..=index],
+		None => &document.pages[..],
+	};
 
-	let images = document
-		.pages
-		.iter()
-		.take(PAGE_LIMIT)
-		.map(|page| {
-			let pixels_per_point = determine_pixels_per_point(page.frame.size()).map_err(to_string)?;
-			let pixmap = typst_render::render(page, pixels_per_point);
-
-			let mut writer = Cursor::new(Vec::new());
-
-			// The unwrap will never fail since `Vec`'s `Write` implementation is infallible.
-			image::write_buffer_with_format(
-				&mut writer,
-				bytemuck::cast_slice(pixmap.pixels()),
-				pixmap.width(),
-				pixmap.height(),
-				image::ColorType::Rgba8,
-				image::ImageFormat::Png,
+	// The warnings may explain why a page is too big, so they're included in the error.
+	let with_warnings = |error: String| {
+		if warnings.is_empty() {
+			error
+		} else {
+			format!(
+				"{error}\n\nThe document also has warnings:\n{}",
+				format_diagnostics(&world, &warnings),
 			)
-			.unwrap();
+		}
+	};
 
-			Ok(writer.into_inner())
-		})
-		.take_while(|image| {
-			if let Ok(image) = image {
-				total_attachment_size += image.len();
-				total_attachment_size <= BYTES_LIMIT
-			} else {
-				true
+	let max_size = options
+		.max_size
+		.map_or(MAX_SIZE, |max_size| max_size.min(MAX_SIZE));
+	let page_limit = options
+		.max_pages
+		.map_or(PAGE_LIMIT, |max| max.min(PAGE_LIMIT));
+	let total = pages.len().min(page_limit);
+
+	let exports = export(&document, &pages[..total], &options.formats)
+		.map_err(|diags| format_diagnostics(&world, &diags))?;
+	// The exports are attached along with the images, so they count toward the same limit.
+	let mut total_attachment_size: usize = exports.iter().map(|export| export.data.len()).sum();
+	if total_attachment_size > BYTES_LIMIT {
+		return Err(with_warnings(format!(
+			"the exported files are {total_attachment_size} bytes, but the limit is {BYTES_LIMIT}"
+		)));
+	}
+	// Without images, every page within the limit is covered by the exports.
+	let (image_encoding, image_pages) = match image_encoding {
+		Some(encoding) => (encoding, page_limit),
+		None => (Encoding::Png, 0),
+	};
+
+	let mut all_blank = true;
+
+	let mut images = Vec::new();
+	let mut sizes = Vec::new();
+	let mut encodings = Vec::new();
+	let mut tiled = Vec::new();
+	let mut rendered_pages = 0;
+	let mut timed_out_page = None;
+	for (i, page) in pages.iter().take(image_pages).enumerate() {
+		let progress = Progress::Rendering { page: i + 1, total };
+		crate::write_progress(progress.clone());
+		wait_for_abandoned_pages(&progress).map_err(with_warnings)?;
+
+		let outlined;
+		let page = if options.boxes {
+			outlined = boxes::outline(page);
+			&outlined
+		} else {
+			page
+		};
+
+		let size = page.frame.size();
+		let mut tiles = None;
+		// Started after waiting for abandoned pages, so the wait doesn't count against this page.
+		let deadline = Instant::now() + PAGE_TIMEOUT;
+		// `None` if the page took too long.
+		let pixmaps = if let Some((_, bounds)) = selection {
+			let pixels_per_point = determine_crop_pixels_per_point(size, bounds, max_size)
+				.map_err(|too_big| with_warnings(too_big.to_string()))?;
+			match render_page(page, pixels_per_point, deadline) {
+				Some(pixmap) => Some(vec![crop(&pixmap, bounds, pixels_per_point)
+					.ok_or("the labelled element has an empty area")?]),
+				None => None,
 			}
-		})
-		.collect::<Result<Vec<_>, String>>()?;
+		} else {
+			match determine_pixels_per_point(size, max_size) {
+				Ok(pixels_per_point) => {
+					render_page(page, pixels_per_point, deadline).map(|pixmap| vec![pixmap])
+				}
+				Err(too_big) if options.tiles => {
+					let (columns, rows) = tile_grid(size, max_size).ok_or_else(|| {
+						with_warnings(format!(
+							"{too_big}, and splitting it would take more than {MAX_TILES} tiles"
+						))
+					})?;
+					tiles = Some(TiledPage {
+						first_image: images.len(),
+						columns,
+						rows,
+					});
+					render_tiles(page, columns, rows, max_size, deadline)
+						.map_err(|too_big| with_warnings(too_big.to_string()))?
+				}
+				Err(too_big) => return Err(with_warnings(too_big.to_string())),
+			}
+		};
+		let Some(mut pixmaps) = pixmaps else {
+			timed_out_page = Some(i + 1);
+			break;
+		};
+
+		if let Some(ratio) = options.aspect {
+			pixmaps = pixmaps
+				.iter()
+				.map(|pixmap| aspect::fit(pixmap, ratio))
+				.collect::<Result<_, _>>()
+				.map_err(with_warnings)?;
+		}
 
-	let more_pages = document.pages.len() - images.len();
+		if let Some(max) = options.max_dimension {
+			pixmaps = pixmaps
+				.into_iter()
+				.map(|pixmap| downscale(pixmap, max))
+				.collect();
+		}
+
+		let mut encoded = Vec::with_capacity(pixmaps.len());
+		let mut page_encodings = Vec::with_capacity(pixmaps.len());
+		let mut page_sizes = Vec::with_capacity(pixmaps.len());
+		for pixmap in &mut pixmaps {
+			all_blank &= is_blank(pixmap.data());
+			if let Some(watermark) = watermark {
+				watermark::apply(pixmap, watermark);
+			}
+			if let Some(colors) = options.colors {
+				quantize(pixmap, colors);
+			}
+			encoded.push(encode(pixmap, image_encoding, options.srgb));
+			page_encodings.push(image_encoding);
+			page_sizes.push((pixmap.width(), pixmap.height()));
+		}
+
+		let fits = |encoded: &[Vec<u8>]| {
+			total_attachment_size + encoded.iter().map(Vec::len).sum::<usize>() <= BYTES_LIMIT
+		};
+		if image_encoding == Encoding::Png && !fits(&encoded) {
+			for &quality in sandbox.jpeg_fallback() {
+				let encoding = Encoding::Jpeg { quality };
+				encoded = pixmaps
+					.iter()
+					.map(|pixmap| encode(pixmap, encoding, options.srgb))
+					.collect();
+				page_encodings.fill(encoding);
+				if fits(&encoded) {
+					break;
+				}
+			}
+		}
+
+		total_attachment_size += encoded.iter().map(Vec::len).sum::<usize>();
+		if total_attachment_size > BYTES_LIMIT
+			|| exports.len() + images.len() + encoded.len() > IMAGE_LIMIT
+		{
+			break;
+		}
+		images.extend(encoded);
+		encodings.extend(page_encodings);
+		sizes.extend(page_sizes);
+		tiled.extend(tiles);
+		rendered_pages += 1;
+	}
+
+	if !images.is_empty() && all_blank {
+		return Err(BLANK_MESSAGE.into());
+	}
+
+	if image_pages == 0 {
+		rendered_pages = total;
+	}
+	let more_pages = pages.len() - rendered_pages;
+
+	let png = image_encoding == Encoding::Png;
+
+	let data_uri = images
+		.first()
+		.filter(|_| options.data_uri && png)
+		.map(|image| to_data_uri(image, encodings[0]));
+
+	let thumbnail = images
+		.first()
+		.filter(|_| options.thumbnail && png)
+		.map(|image| make_thumbnail(image));
 
 	Ok(Rendered {
 		images,
+		sizes,
+		encodings,
 		more_pages,
 		warnings: format_diagnostics(&world, &warnings),
+		warning_count: warnings.len(),
+		data_uri,
+		thumbnail,
+		tiled,
+		timed_out_page,
+		exports,
+	})
+}
+
+/// Compiles the document and returns its first page, naming the document in any error.
+fn first_page(sandbox: &Sandbox, source: String, name: &str) -> Result<Page, String> {
+	let world = sandbox.with_source(source);
+	let document = typst::compile(&world).output.map_err(|diags| {
+		format!(
+			"in the {name} code:\n{}",
+			format_diagnostics(&world, &diags)
+		)
+	})?;
+	document
+		.pages
+		.into_iter()
+		.next()
+		.ok_or_else(|| format!("the {name} code has no pages"))
+}
+
+/// The color of pixels that differ between the two pages.
+const DIFF_HIGHLIGHT: [u8; 4] = [255, 0, 255, 255];
+
+/// Composites the pixel over white, then lightens it so that highlighted pixels stand out.
+fn fade(pixel: PremultipliedColorU8) -> PremultipliedColorU8 {
+	let fade_channel = |channel: u8| {
+		let opaque = channel + (255 - pixel.alpha());
+		255 - (255 - opaque) / 4
+	};
+	PremultipliedColorU8::from_rgba(
+		fade_channel(pixel.red()),
+		fade_channel(pixel.green()),
+		fade_channel(pixel.blue()),
+		255,
+	)
+	.unwrap()
+}
+
+/// Overlays the pixmaps, padding the smaller one, and returns the result along with the number of
+/// pixels that differ.
+fn diff_pixmaps(before: &Pixmap, after: &Pixmap) -> (Pixmap, usize) {
+	let width = before.width().max(after.width());
+	let height = before.height().max(after.height());
+	// Never fails since both pixmaps have a non-zero size.
+	let mut output = Pixmap::new(width, height).unwrap();
+
+	let [r, g, b, a] = DIFF_HIGHLIGHT;
+	let highlight = PremultipliedColorU8::from_rgba(r, g, b, a).unwrap();
+
+	let mut changed = 0;
+	let pixels = output.pixels_mut();
+	for y in 0..height {
+		for x in 0..width {
+			let before = before.pixel(x, y);
+			let after = after.pixel(x, y);
+			let pixel = match (before, after) {
+				(Some(before), Some(after)) if before == after => fade(after),
+				_ => {
+					changed += 1;
+					highlight
+				}
+			};
+			pixels[(y * width + x) as usize] = pixel;
+		}
+	}
+
+	(output, changed)
+}
+
+#[test]
+fn test_diff_pixmaps() {
+	let mut before = Pixmap::new(2, 1).unwrap();
+	before.fill(tiny_skia::Color::WHITE);
+	let mut after = Pixmap::new(2, 2).unwrap();
+	after.fill(tiny_skia::Color::WHITE);
+	after.pixels_mut()[1] = PremultipliedColorU8::from_rgba(0, 0, 0, 255).unwrap();
+
+	let (output, changed) = diff_pixmaps(&before, &after);
+	assert_eq!((output.width(), output.height()), (2, 2));
+	// The changed pixel and the two only covered by `after`.
+	assert_eq!(changed, 3);
+	assert_eq!(output.pixels()[0], fade(before.pixels()[0]));
+}
+
+pub fn diff(sandbox: &Sandbox, before: String, after: String) -> Result<DiffImage, String> {
+	check_source_length(sandbox, &before)?;
+	check_source_length(sandbox, &after)?;
+	crate::write_progress(Progress::Compiling);
+	let before = first_page(sandbox, before, "first")?;
+	let after = first_page(sandbox, after, "second")?;
+
+	// Render both at the same scale so that identical content lines up.
+	let pixels_per_point = determine_pixels_per_point(before.frame.size(), MAX_SIZE)
+		.map_err(to_string)?
+		.min(determine_pixels_per_point(after.frame.size(), MAX_SIZE).map_err(to_string)?);
+
+	crate::write_progress(Progress::Rendering { page: 1, total: 1 });
+	let before = typst_render::render(&before, pixels_per_point);
+	let after = typst_render::render(&after, pixels_per_point);
+
+	let (output, changed_pixels) = diff_pixmaps(&before, &after);
+	let size_mismatch =
+		(before.width() != after.width() || before.height() != after.height()).then(|| {
+			(
+				(before.width(), before.height()),
+				(after.width(), after.height()),
+			)
+		});
+
+	Ok(DiffImage {
+		image: encode_png(&output, false),
+		changed_pixels,
+		size_mismatch,
 	})
 }
+
+#[test]
+fn test_emoji() {
+	let sandbox = Sandbox::with_cache_directory(std::env::temp_dir().join("typst-bot-test-cache"));
+
+	let source = "#set page(width: auto, height: auto, margin: 0pt)\n#text(size: 40pt)[😀]";
+	let rendered = render(&sandbox, None, source.into(), &RenderOptions::default()).unwrap();
+
+	// The missing glyph box is black, so some pixel being colorful means the emoji was drawn.
+	let image = image::load_from_memory(&rendered.images[0])
+		.unwrap()
+		.to_rgba8();
+	assert!(image
+		.pixels()
+		.any(|&image::Rgba([r, g, b, _])| r.abs_diff(b) > 64 || g.abs_diff(b) > 64));
+}
@@ -0,0 +1,36 @@
+use typst::diag::SourceDiagnostic;
+use typst::syntax::ast::{self, AstNode as _};
+use typst::syntax::{Source, SyntaxNode};
+
+/// Finds the imports and includes of packages in the source, which are forbidden in safe mode.
+///
+/// Only packages named by string literals can be found this way,
+/// so the sandbox also refuses to load packages in safe mode.
+pub fn package_imports(source: &Source) -> Vec<SourceDiagnostic> {
+	let mut errors = Vec::new();
+	collect(source.root(), &mut errors);
+	errors
+}
+
+fn collect(node: &SyntaxNode, errors: &mut Vec<SourceDiagnostic>) {
+	let target = if let Some(import) = node.cast::<ast::ModuleImport>() {
+		Some(import.source())
+	} else {
+		node
+			.cast::<ast::ModuleInclude>()
+			.map(|include| include.source())
+	};
+
+	if let Some(ast::Expr::Str(path)) = target {
+		if path.get().starts_with('@') {
+			errors.push(
+				SourceDiagnostic::error(path.span(), "packages can't be used in safe mode")
+					.with_hint("only the standard library is available"),
+			);
+		}
+	}
+
+	for child in node.children() {
+		collect(child, errors);
+	}
+}
@@ -1,11 +1,14 @@
-use std::collections::HashMap;
-use std::path::PathBuf;
+use std::collections::{HashMap, VecDeque};
+use std::io::Read;
+use std::path::{Path, PathBuf};
 use std::sync::Mutex;
+use std::time::{Duration, Instant};
 
+use protocol::{ConfigResponse, Progress};
 use typst::diag::{eco_format, FileError, FileResult, PackageError, PackageResult};
-use typst::foundations::{Bytes, Datetime};
-use typst::syntax::package::PackageSpec;
-use typst::syntax::{FileId, Source};
+use typst::foundations::{Bytes, Datetime, Dict, IntoValue as _};
+use typst::syntax::package::{PackageManifest, PackageSpec};
+use typst::syntax::{FileId, Source, VirtualPath};
 use typst::text::{Font, FontBook};
 use typst::utils::LazyHash;
 use typst::Library;
@@ -40,20 +43,139 @@ pub struct Sandbox {
 	cache_directory: PathBuf,
 	http: ureq::Agent,
 	files: Mutex<HashMap<FileId, FileEntry>>,
+
+	/// The longest source that will be compiled, in bytes.
+	max_source_length: Option<usize>,
+	/// The JPEG qualities to try, in order, for images whose PNGs are too large.
+	jpeg_fallback: Vec<u8>,
+	/// The latest source for each recent session, oldest first.
+	sessions: Mutex<VecDeque<(u64, Source)>>,
+}
+
+/// The number of sessions whose sources are kept for incremental compilation.
+const MAX_SESSIONS: usize = 32;
+
+/// Example files that every document can read, like `#image("example.png")`,
+/// so that snippets from tutorials work without uploading anything.
+/// Keep this small, since it's part of the worker binary, and keep `?help render` in sync with it.
+const BUNDLED_ASSETS: &[(&str, &[u8])] = &[
+	// A 120x80 landscape with a sun.
+	("example.png", include_bytes!("../assets/example.png")),
+	// A table of fruits with their colors and prices.
+	("example.csv", include_bytes!("../assets/example.csv")),
+];
+
+/// The bundled asset at the path, which is outside of any package.
+fn bundled_asset(id: FileId) -> Option<&'static [u8]> {
+	if id.package().is_some() {
+		return None;
+	}
+	let path = id.vpath().as_rootless_path();
+	BUNDLED_ASSETS
+		.iter()
+		.find(|(name, _)| Path::new(name) == path)
+		.map(|&(_, bytes)| bytes)
 }
 
-fn fonts() -> Vec<Font> {
-	typst_assets::fonts()
-		.flat_map(|bytes| {
-			let buffer = Bytes::from_static(bytes);
-			let face_count = ttf_parser::fonts_in_collection(&buffer).unwrap_or(1);
-			(0..face_count).map(move |face| {
-				Font::new(buffer.clone(), face).expect("failed to load font from typst-assets")
-			})
+#[test]
+fn test_bundled_asset() {
+	let id = |path| FileId::new(None, VirtualPath::new(path));
+	assert!(bundled_asset(id("example.png")).is_some());
+	assert!(bundled_asset(id("/example.csv")).is_some());
+	assert!(bundled_asset(id("nested/example.png")).is_none());
+	assert!(bundled_asset(id("missing.png")).is_none());
+}
+
+/// Reads the font files in the directory given by the `FONT_DIRECTORY` env var, if it's set,
+/// along with their paths.
+fn custom_font_files() -> Result<Vec<(PathBuf, Bytes)>, String> {
+	let Some(directory) = std::env::var_os("FONT_DIRECTORY") else {
+		return Ok(Vec::new());
+	};
+
+	let entries = std::fs::read_dir(&directory)
+		.map_err(|error| format!("reading font directory {directory:?}: {error}"))?;
+
+	let files = entries
+		.filter_map(|entry| {
+			let path = entry.ok()?.path();
+			let extension = path.extension()?.to_str()?.to_ascii_lowercase();
+			if !matches!(extension.as_str(), "ttf" | "otf" | "ttc" | "otc") {
+				return None;
+			}
+			match std::fs::read(&path) {
+				Ok(contents) => Some((path, Bytes::from(contents))),
+				Err(error) => {
+					eprintln!("skipping font {path:?}: {error}");
+					None
+				}
+			}
+		})
+		.collect();
+	Ok(files)
+}
+
+/// Parses each face in the font file, skipping those that fail to parse
+/// so that one corrupt file doesn't take down the worker.
+fn faces(buffer: Bytes, path: Option<&Path>) -> Vec<Font> {
+	let face_count = ttf_parser::fonts_in_collection(&buffer).unwrap_or(1);
+	(0..face_count)
+		.filter_map(|face| {
+			let font = Font::new(buffer.clone(), face);
+			if font.is_none() {
+				let file = path.map_or_else(|| "a bundled font".into(), |path| format!("{path:?}"));
+				eprintln!("skipping face {face} of {file}: failed to parse");
+			}
+			font
 		})
 		.collect()
 }
 
+/// Noto Color Emoji, under the license in `assets/NotoColorEmoji-OFL.txt`.
+///
+/// None of the fonts from `typst-assets` have emoji, so without this they render as boxes.
+/// Typst falls back to this font for any characters that the chosen font doesn't have, which includes emoji.
+static EMOJI_FONT: &[u8] = include_bytes!("../assets/NotoColorEmoji.ttf");
+
+/// Reads the comma-separated font families to leave out from the `EXCLUDED_FONTS` env var,
+/// lowercased to match them regardless of case.
+fn excluded_families() -> Vec<String> {
+	let Ok(families) = std::env::var("EXCLUDED_FONTS") else {
+		return Vec::new();
+	};
+	families
+		.split(',')
+		.map(|family| family.trim().to_lowercase())
+		.filter(|family| !family.is_empty())
+		.collect()
+}
+
+/// Loads the bundled and configured fonts, failing if any can't be read or none are left.
+fn fonts() -> Result<Vec<Font>, String> {
+	let bundled = typst_assets::fonts()
+		.chain([EMOJI_FONT])
+		.flat_map(|buffer| faces(Bytes::from_static(buffer), None));
+	let custom = custom_font_files()?
+		.into_iter()
+		.flat_map(|(path, buffer)| faces(buffer, Some(&path)));
+	// Excluded fonts are left out of the book entirely, so Typst never falls back to them.
+	let excluded = excluded_families();
+	let fonts: Vec<_> = bundled
+		.chain(custom)
+		.filter(|font| !excluded.contains(&font.info().family.to_lowercase()))
+		.collect();
+
+	// Without any fonts, every render would silently come out as missing glyphs, so fail right away instead.
+	if fonts.is_empty() {
+		return Err(
+			"no fonts were loaded; check that `typst-assets` is built with its `fonts` feature, \
+			 set `FONT_DIRECTORY` to a directory of fonts, or exclude fewer fonts with `EXCLUDED_FONTS`"
+				.into(),
+		);
+	}
+	Ok(fonts)
+}
+
 fn make_source(source: String) -> Source {
 	Source::detached(source)
 }
@@ -67,6 +189,22 @@ fn http_successful(status: u16) -> bool {
 	status / 100 == 2
 }
 
+/// The names of the directories within `path`, or nothing if it can't be read.
+fn subdirectories(path: &Path) -> Vec<String> {
+	let Ok(entries) = std::fs::read_dir(path) else {
+		return Vec::new();
+	};
+	entries
+		.filter_map(|entry| {
+			let entry = entry.ok()?;
+			if !entry.file_type().ok()?.is_dir() {
+				return None;
+			}
+			entry.file_name().into_string().ok()
+		})
+		.collect()
+}
+
 fn retry<T, E>(mut f: impl FnMut() -> Result<T, E>) -> Result<T, E> {
 	if let Ok(ok) = f() {
 		Ok(ok)
@@ -75,34 +213,274 @@ fn retry<T, E>(mut f: impl FnMut() -> Result<T, E>) -> Result<T, E> {
 	}
 }
 
+/// Wraps the body of a package download to report how much of it has been read.
+struct CountingReader<R> {
+	inner: R,
+	downloaded: u64,
+	total: Option<u64>,
+	last_report: Instant,
+}
+
+impl<R> CountingReader<R> {
+	/// The least time between progress reports, so the bot isn't flooded with them.
+	const REPORT_INTERVAL: Duration = Duration::from_millis(500);
+
+	fn new(inner: R, total: Option<u64>) -> Self {
+		Self {
+			inner,
+			downloaded: 0,
+			total,
+			last_report: Instant::now(),
+		}
+	}
+}
+
+impl<R: Read> Read for CountingReader<R> {
+	fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+		let read = self.inner.read(buf)?;
+		self.downloaded += read as u64;
+
+		if self.last_report.elapsed() >= Self::REPORT_INTERVAL {
+			self.last_report = Instant::now();
+			crate::write_progress(Progress::DownloadedBytes {
+				downloaded: self.downloaded,
+				total: self.total,
+			});
+		}
+
+		Ok(read)
+	}
+}
+
+/// Reads a timeout for package downloads, as a positive number of seconds, from the env var.
+fn http_timeout(var: &str, default: Duration) -> Duration {
+	let Some(seconds) = std::env::var_os(var) else {
+		return default;
+	};
+
+	seconds
+		.to_str()
+		.and_then(|seconds| seconds.parse().ok())
+		.filter(|&seconds| seconds > 0)
+		.map(Duration::from_secs)
+		.unwrap_or_else(|| panic!("`{var}` must be a positive number of seconds"))
+}
+
+fn connect_timeout() -> Duration {
+	http_timeout("PACKAGE_CONNECT_TIMEOUT", Duration::from_secs(5))
+}
+
+fn read_timeout() -> Duration {
+	http_timeout("PACKAGE_READ_TIMEOUT", Duration::from_secs(10))
+}
+
+fn user_agent() -> String {
+	std::env::var("PACKAGE_USER_AGENT").unwrap_or_else(|_| {
+		format!(
+			"typst-bot/{} (typst {}; +https://github.com/mattfbacon/typst-bot)",
+			env!("CARGO_PKG_VERSION"),
+			env!("TYPST_VERSION"),
+		)
+	})
+}
+
+/// Makes the agent for downloading packages, so that a hung connection to the registry fails
+/// quickly rather than stalling the render until the bot gives up on it.
+fn http_agent() -> ureq::Agent {
+	ureq::AgentBuilder::new()
+		.timeout_connect(connect_timeout())
+		.timeout_read(read_timeout())
+		.user_agent(&user_agent())
+		.build()
+}
+
+/// Reads the comma-separated JPEG qualities to fall back to from the env var.
+/// Unset means only PNG is used.
+fn jpeg_fallback() -> Vec<u8> {
+	let Ok(qualities) = std::env::var("JPEG_FALLBACK_QUALITIES") else {
+		return Vec::new();
+	};
+
+	qualities
+		.split(',')
+		.map(|quality| {
+			quality
+				.trim()
+				.parse()
+				.ok()
+				.filter(|quality| (1..=100).contains(quality))
+				.expect("`JPEG_FALLBACK_QUALITIES` must be comma-separated qualities from 1 to 100")
+		})
+		.collect()
+}
+
+/// Reads the `CACHE_DIRECTORY` env var, creating the directory if needed and checking that it's
+/// writable, so that a misconfigured cache fails at startup rather than partway through a render.
+fn cache_directory() -> PathBuf {
+	let directory =
+		PathBuf::from(std::env::var_os("CACHE_DIRECTORY").expect("need the `CACHE_DIRECTORY` env var"));
+
+	let check = std::fs::create_dir_all(&directory).and_then(|()| {
+		let probe = directory.join(".write-test");
+		std::fs::write(&probe, [])?;
+		std::fs::remove_file(&probe)
+	});
+	if let Err(error) = check {
+		panic!(
+			"the cache directory {} must be writable: {error}",
+			directory.display(),
+		);
+	}
+
+	directory
+}
+
+/// The error for failing to write a package to the cache, as opposed to failing to download it.
+fn cache_error(error: impl std::fmt::Display) -> PackageError {
+	PackageError::Other(Some(eco_format!(
+		"failed to save the package to the cache ({error}); this is a problem with the bot, not your code"
+	)))
+}
+
 pub struct WithSource<'a> {
 	sandbox: &'a Sandbox,
 	source: Source,
 	time: time::OffsetDateTime,
+	/// Overrides the sandbox's library, such as to provide inputs.
+	library: Option<LazyHash<Library>>,
+	packages_allowed: bool,
 }
 
 impl Sandbox {
 	pub fn new() -> Self {
-		let fonts = fonts();
+		Self::with_cache_directory(cache_directory())
+	}
+
+	/// Like [`Self::new`], but caches packages in the given directory rather than `CACHE_DIRECTORY`.
+	pub fn with_cache_directory(cache_directory: PathBuf) -> Self {
+		let fonts = fonts().unwrap_or_else(|error| panic!("{error}"));
 
 		Self {
 			library: LazyHash::new(Library::default()),
 			book: LazyHash::new(FontBook::from_fonts(&fonts)),
 			fonts,
 
-			cache_directory: std::env::var_os("CACHE_DIRECTORY")
-				.expect("need the `CACHE_DIRECTORY` env var")
-				.into(),
-			http: ureq::Agent::new(),
+			cache_directory,
+			http: http_agent(),
 			files: Mutex::new(HashMap::new()),
+
+			max_source_length: std::env::var("MAX_SOURCE_LENGTH").ok().map(|length| {
+				length
+					.parse()
+					.expect("`MAX_SOURCE_LENGTH` must be a number of bytes")
+			}),
+			jpeg_fallback: jpeg_fallback(),
+			sessions: Mutex::new(VecDeque::new()),
+		}
+	}
+
+	pub fn max_source_length(&self) -> Option<usize> {
+		self.max_source_length
+	}
+
+	pub fn jpeg_fallback(&self) -> &[u8] {
+		&self.jpeg_fallback
+	}
+
+	/// Reports the settings in effect, given whether there's a watermark.
+	pub fn config(&self, watermark: bool) -> ConfigResponse {
+		let env_path =
+			|var| std::env::var_os(var).map(|path| PathBuf::from(path).display().to_string());
+
+		ConfigResponse {
+			cache_directory: self.cache_directory.display().to_string(),
+			cached_packages: self.cached_packages().len(),
+			fonts: self.fonts.len(),
+			font_directory: env_path("FONT_DIRECTORY"),
+			excluded_fonts: excluded_families(),
+			watermark,
+			jpeg_fallback: self.jpeg_fallback.clone(),
+			max_source_length: self.max_source_length,
+			package_connect_timeout: connect_timeout().as_secs(),
+			package_read_timeout: read_timeout().as_secs(),
+			package_user_agent: user_agent(),
 		}
 	}
 
+	/// Loads the fonts again, picking up any changes to the font directory.
+	///
+	/// Returns the number of fonts now available.
+	/// If they can't be loaded, the previous fonts are kept.
+	pub fn reload_fonts(&mut self) -> Result<usize, String> {
+		let fonts = fonts()?;
+		self.book = LazyHash::new(FontBook::from_fonts(&fonts));
+		self.fonts = fonts;
+		Ok(self.fonts.len())
+	}
+
 	pub fn with_source(&self, source: String) -> WithSource<'_> {
+		self.with_main_source(make_source(source))
+	}
+
+	/// Like `with_source`, but edits the session's previous source, if any, to match the new one
+	/// instead of starting from scratch, so that only the changed part is reparsed.
+	pub fn with_session_source(&self, session: u64, text: String) -> WithSource<'_> {
+		let mut sessions = self.sessions.lock().unwrap();
+		let source = match sessions.iter().position(|&(id, _)| id == session) {
+			Some(index) => {
+				let (_, mut source) = sessions.remove(index).unwrap();
+				source.replace(&text);
+				source
+			}
+			None => make_source(text),
+		};
+		sessions.push_back((session, source.clone()));
+		if sessions.len() > MAX_SESSIONS {
+			sessions.pop_front();
+		}
+		drop(sessions);
+
+		self.with_main_source(source)
+	}
+
+	/// Uses the template entrypoint of the package as the main source, downloading the package if needed.
+	///
+	/// The source keeps its place in the package so that the template's relative imports work.
+	pub fn with_template(&self, spec: &str) -> Result<WithSource<'_>, String> {
+		let spec: PackageSpec = spec
+			.parse()
+			.map_err(|error| format!("invalid package: {error}"))?;
+
+		let manifest_id = FileId::new(Some(spec.clone()), VirtualPath::new("typst.toml"));
+		let manifest = self
+			.file(manifest_id, |file| file.bytes.clone())
+			.map_err(|error| error.to_string())?;
+		let manifest = std::str::from_utf8(&manifest).map_err(|_| "the manifest is not valid UTF-8")?;
+		let manifest: PackageManifest =
+			toml::from_str(manifest).map_err(|error| format!("invalid manifest: {error}"))?;
+		let Some(template) = manifest.template else {
+			return Err(format!(
+				"`{spec}` is not a template package; import it with `#import \"{spec}\"` instead"
+			));
+		};
+
+		let entrypoint = VirtualPath::new(format!("{}/{}", template.path, template.entrypoint));
+		let id = FileId::new(Some(spec), entrypoint);
+		let source = self
+			.file(id, |file| file.source(id))
+			.and_then(|source| source)
+			.map_err(|error| error.to_string())?;
+
+		Ok(self.with_main_source(source))
+	}
+
+	fn with_main_source(&self, source: Source) -> WithSource<'_> {
 		WithSource {
 			sandbox: self,
-			source: make_source(source),
+			source,
 			time: get_time(),
+			library: None,
+			packages_allowed: true,
 		}
 	}
 
@@ -116,7 +494,9 @@ impl Sandbox {
 		}
 
 		eprintln!("downloading {package}");
-		crate::write_progress(format!("downloading {package}"));
+		crate::write_progress(Progress::DownloadingPackage {
+			spec: package.to_string(),
+		});
 
 		let url = format!(
 			"https://packages.typst.org/{}/{}-{}.tar.gz",
@@ -141,23 +521,45 @@ impl Sandbox {
 		})
 		.map_err(|error| PackageError::NetworkFailed(Some(error)))?;
 
+		let total = response
+			.header("Content-Length")
+			.and_then(|length| length.parse().ok());
 		let mut compressed_archive = Vec::new();
-		response
-			.into_reader()
+		CountingReader::new(response.into_reader(), total)
 			.read_to_end(&mut compressed_archive)
 			.map_err(|error| PackageError::NetworkFailed(Some(eco_format!("{error}"))))?;
 		let raw_archive = zune_inflate::DeflateDecoder::new(&compressed_archive)
 			.decode_gzip()
 			.map_err(|error| PackageError::MalformedArchive(Some(eco_format!("{error}"))))?;
+		std::fs::create_dir_all(&path).map_err(cache_error)?;
 		let mut archive = tar::Archive::new(raw_archive.as_slice());
 		archive.unpack(&path).map_err(|error| {
 			_ = std::fs::remove_dir_all(&path);
-			PackageError::MalformedArchive(Some(eco_format!("{error}")))
+			if error.kind() == std::io::ErrorKind::PermissionDenied {
+				cache_error(error)
+			} else {
+				PackageError::MalformedArchive(Some(eco_format!("{error}")))
+			}
 		})?;
 
 		Ok(path)
 	}
 
+	/// Lists the packages that have been unpacked into the cache directory, in sorted order.
+	pub fn cached_packages(&self) -> Vec<String> {
+		let mut packages = Vec::new();
+		for namespace in subdirectories(&self.cache_directory) {
+			let namespace_dir = self.cache_directory.join(&namespace);
+			for name in subdirectories(&namespace_dir) {
+				for version in subdirectories(&namespace_dir.join(&name)) {
+					packages.push(format!("@{namespace}/{name}:{version}"));
+				}
+			}
+		}
+		packages.sort();
+		packages
+	}
+
 	// Weird pattern because mapping a MutexGuard is not stable yet.
 	fn file<T>(&self, id: FileId, map: impl FnOnce(&mut FileEntry) -> T) -> FileResult<T> {
 		let mut files = self.files.lock().unwrap();
@@ -167,19 +569,35 @@ impl Sandbox {
 		// `files` must stay locked here so we don't download the same package multiple times.
 		// TODO proper multithreading, maybe with typst-kit.
 
-		'x: {
-			if let Some(package) = id.package() {
-				let package_dir = self.ensure_package(package)?;
-				let Some(path) = id.vpath().resolve(&package_dir) else {
-					break 'x;
-				};
-				let contents = std::fs::read(&path).map_err(|error| FileError::from_io(error, &path))?;
-				let entry = files.entry(id).or_insert(FileEntry {
-					bytes: contents.into(),
-					source: None,
-				});
-				return Ok(map(entry));
-			}
+		if let Some(package) = id.package() {
+			let package_dir = self.ensure_package(package)?;
+			// Refer to the file by its path within the package rather than its path in the cache directory,
+			// so users can tell whether the package or the file within it is wrong.
+			let not_found = || {
+				let path = format!("{package}{}", id.vpath().as_rooted_path().display());
+				FileError::NotFound(path.into())
+			};
+			let path = id.vpath().resolve(&package_dir).ok_or_else(not_found)?;
+			let contents = std::fs::read(&path).map_err(|error| {
+				if error.kind() == std::io::ErrorKind::NotFound {
+					not_found()
+				} else {
+					FileError::from_io(error, &path)
+				}
+			})?;
+			let entry = files.entry(id).or_insert(FileEntry {
+				bytes: contents.into(),
+				source: None,
+			});
+			return Ok(map(entry));
+		}
+
+		if let Some(bytes) = bundled_asset(id) {
+			let entry = files.entry(id).or_insert(FileEntry {
+				bytes: Bytes::from_static(bytes),
+				source: None,
+			});
+			return Ok(map(entry));
 		}
 
 		Err(FileError::NotFound(id.vpath().as_rootless_path().into()))
@@ -190,11 +608,42 @@ impl WithSource<'_> {
 	pub fn main_source(&self) -> &Source {
 		&self.source
 	}
+
+	/// Makes the document reproducible given the seed.
+	///
+	/// Typst has no random number generator of its own to seed,
+	/// so this pins the current date to the Unix epoch and exposes the seed as `sys.inputs.seed`
+	/// for documents and packages that generate random numbers themselves.
+	#[must_use]
+	pub fn seeded(mut self, seed: i64) -> Self {
+		let inputs = Dict::from_iter([("seed".into(), seed.into_value())]);
+		self.library = Some(LazyHash::new(
+			Library::builder().with_inputs(inputs).build(),
+		));
+		self.time = time::OffsetDateTime::UNIX_EPOCH;
+		self
+	}
+
+	/// Makes any attempt to load a file from a package fail.
+	#[must_use]
+	pub fn without_packages(mut self) -> Self {
+		self.packages_allowed = false;
+		self
+	}
+
+	fn check_package(&self, id: FileId) -> FileResult<()> {
+		if id.package().is_some() && !self.packages_allowed {
+			return Err(FileError::Other(Some(eco_format!(
+				"packages can't be used in safe mode"
+			))));
+		}
+		Ok(())
+	}
 }
 
 impl typst::World for WithSource<'_> {
 	fn library(&self) -> &LazyHash<Library> {
-		&self.sandbox.library
+		self.library.as_ref().unwrap_or(&self.sandbox.library)
 	}
 
 	fn main(&self) -> FileId {
@@ -205,6 +654,7 @@ impl typst::World for WithSource<'_> {
 		if id == self.source.id() {
 			Ok(self.source.clone())
 		} else {
+			self.check_package(id)?;
 			self.sandbox.file(id, |file| file.source(id))?
 		}
 	}
@@ -218,6 +668,7 @@ impl typst::World for WithSource<'_> {
 	}
 
 	fn file(&self, id: FileId) -> FileResult<Bytes> {
+		self.check_package(id)?;
 		self.sandbox.file(id, |file| file.bytes.clone())
 	}
 
@@ -0,0 +1,60 @@
+use protocol::syntax::string_literal;
+use tiny_skia::{Pixmap, PixmapPaint, Transform};
+
+use crate::diagnostic::format_diagnostics;
+use crate::sandbox::Sandbox;
+
+const PIXELS_PER_POINT: f32 = 2.0;
+const OPACITY: f32 = 0.6;
+/// The distance between the watermark and the corner of the page, in pixels.
+const INSET: i32 = 4;
+
+/// Renders the text of the `WATERMARK` env var, if it's set, so it can be stamped onto every page.
+///
+/// The watermark is composited onto the rasterized output rather than injected into the preamble
+/// so that user code can't hide or restyle it.
+pub fn load(sandbox: &Sandbox) -> Option<Pixmap> {
+	let text = std::env::var("WATERMARK")
+		.ok()
+		.filter(|text| !text.is_empty())?;
+
+	let source = format!(
+		concat!(
+			"#set page(width: auto, height: auto, margin: 2pt, fill: none)\n",
+			"#set text(size: 8pt, fill: gray)\n",
+			"#{}\n",
+		),
+		string_literal(&text),
+	);
+	let world = sandbox.with_source(source);
+	let document = typst::compile(&world).output.unwrap_or_else(|errors| {
+		panic!(
+			"failed to render the watermark:\n{}",
+			format_diagnostics(&world, &errors)
+		)
+	});
+	let page = document.pages.first()?;
+
+	Some(typst_render::render(page, PIXELS_PER_POINT))
+}
+
+/// Stamps the watermark in the bottom-right corner of the page.
+pub fn apply(page: &mut Pixmap, watermark: &Pixmap) {
+	// Pixmap dimensions are always far below `i32::MAX`.
+	#![allow(clippy::cast_possible_wrap)]
+
+	let x = page.width() as i32 - watermark.width() as i32 - INSET;
+	let y = page.height() as i32 - watermark.height() as i32 - INSET;
+	let paint = PixmapPaint {
+		opacity: OPACITY,
+		..PixmapPaint::default()
+	};
+	page.draw_pixmap(
+		x,
+		y,
+		watermark.as_ref(),
+		&paint,
+		Transform::identity(),
+		None,
+	);
+}